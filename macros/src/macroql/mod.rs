@@ -1,6 +1,7 @@
 use convert_case::{Case, Casing};
 use proc_macro::TokenStream;
 use quote::{format_ident, quote};
+use sha2::{Digest, Sha256};
 use syn::{
     parse::{Parse, ParseStream},
     parse_macro_input,
@@ -39,34 +40,31 @@ pub fn macroql(input: TokenStream) -> TokenStream {
     let sels_fmt_gq = &sels.fmt_gq()[4..];
     let sels_fmt_rs = sels.fmt_rs();
     let query_str = format!("{oper} {name}{vars_fmt_gq}{sels_fmt_gq}");
+    let hash = Sha256::digest(&query_str)
+        .iter()
+        .map(|e| format!("{e:02x}"))
+        .collect::<String>();
+    let kind = ident_to_case(&oper, Case::Pascal);
+    let name_str = name.to_string();
+    let document = quote! {
+        crate::graphql::Document {
+            kind: crate::graphql::Kind::#kind,
+            name: #name_str,
+            hash: #hash,
+            query: #query_str,
+        }
+    };
     quote! {
-        #visi async fn #name(client: reqwest::Client, vars: #name::Vars) -> anyhow::Result<#name::Sels> {
-            #[derive(serde::Serialize)]
-            struct Request {
-                query: &'static str,
-                variables: #name::Vars,
-            }
-            #[derive(serde::Deserialize)]
-            struct Success {
-                data: #name::Sels,
-            }
-            #[derive(serde::Deserialize)]
-            struct Failure {
-                errors: Vec<Error>,
-            }
-            #[derive(serde::Deserialize)]
-            struct Error {
-                message: String,
-            }
-            let res = client.post("https://page.kakao.com/graphql").json(&Request { query: #query_str, variables: vars }).send().await?.json::<serde_json::Value>().await?;
-            if res.get("errors").is_some() {
-                Err(anyhow::anyhow!("{}", serde_json::from_value::<Failure>(res)?.errors.into_iter().map(|e| e.message).collect::<Vec<_>>().join(", ")))
-            } else {
-                Ok(serde_json::from_value::<Success>(res)?.data)
-            }
+        #visi async fn #name(client: crate::graphql::Upstream, vars: #name::Vars) -> anyhow::Result<#name::Sels> {
+            crate::graphql::execute(client, &#name::OPERATION, vars).await
         }
 
         #visi mod #name {
+            pub static OPERATION: crate::graphql::Operation<Vars, Sels> =
+                crate::graphql::Operation::new(#document);
+
+            inventory::submit!(#document);
+
             #vars_fmt_rs
             #sels_fmt_rs
         }
@@ -143,6 +143,7 @@ impl Sel {
                     quote! {
                         #[derive(Debug, serde::Deserialize)]
                         #[serde(tag = "__typename")]
+                        #[allow(clippy::enum_variant_names)]
                         pub enum #typ_name {
                             #(#variants,)*
                             #[serde(other)]
@@ -0,0 +1,60 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::states::{
+    account::{Reward, RewardKind},
+    States,
+};
+
+use super::Result;
+
+#[derive(Deserialize)]
+pub struct RewardsReq {
+    account: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct RewardsRes {
+    account: i64,
+    balance: i64,
+    balance_gained: i64,
+    tickets_received: i64,
+    rewards: Vec<Reward>,
+}
+
+pub async fn rewards(
+    State(state): State<Arc<States>>,
+    Query(query): Query<RewardsReq>,
+) -> Result<Json<Vec<RewardsRes>>> {
+    let accounts = if let Some(account) = query.account {
+        state.get_acc(account)?;
+        vec![account]
+    } else {
+        let mut accounts = state.accounts.iter().map(|e| *e.key()).collect::<Vec<_>>();
+        accounts.sort();
+        accounts
+    };
+    let mut list = Vec::new();
+    for account in accounts {
+        let rewards = state.get_acc(account)?.rewards.clone();
+        list.push(RewardsRes {
+            account,
+            balance: state.get_acc(account)?.balance,
+            balance_gained: rewards.iter().map(|e| e.balance_change).sum(),
+            tickets_received: rewards
+                .iter()
+                .map(|e| match e.kind {
+                    RewardKind::Gift { ticket_count, .. } => ticket_count,
+                    RewardKind::Gotcha { .. } => 0,
+                })
+                .sum(),
+            rewards,
+        });
+    }
+    Ok(Json(list))
+}
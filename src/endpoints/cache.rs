@@ -0,0 +1,9 @@
+use std::collections::HashMap;
+
+use axum::Json;
+
+use crate::graphql::cache::{stats, CacheStats};
+
+pub async fn cache() -> Json<HashMap<String, CacheStats>> {
+    Json(stats())
+}
@@ -0,0 +1,61 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    Extension, Json,
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::states::{
+    auth::{Caller, Scope},
+    series::{Episode, Series},
+    States,
+};
+
+use super::{HttpError, Result};
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EpisodesReq {
+    series_id: i64,
+    // walks every page again instead of only looking for new episodes, which
+    // is a single hit against the rate limit, so it is kept for admin keys
+    #[serde(default)]
+    full: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct EpisodesRes {
+    list: Vec<Episode>,
+    checked_at: i64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/episodes",
+    params(EpisodesReq),
+    responses(
+        (status = 200, body = EpisodesRes),
+        (status = 403, description = "full was asked for by a key that is not an admin key")
+    )
+)]
+pub async fn episodes(
+    State(state): State<Arc<States>>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<EpisodesReq>,
+) -> Result<Json<EpisodesRes>> {
+    if query.full && caller.scope < Scope::Admin {
+        Err(HttpError::new(
+            StatusCode::FORBIDDEN,
+            format!("api key {} may not walk every page", caller.name),
+        ))?
+    }
+    let list = Series::sync_episodes(&state, query.series_id, query.full).await?;
+    let checked_at = state
+        .serieses
+        .get(&query.series_id)
+        .map_or(0, |e| e.episodes_checked_at);
+    Ok(Json(EpisodesRes { list, checked_at }))
+}
@@ -0,0 +1,109 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::spawn;
+
+use crate::{
+    scheduler::JobInfo,
+    states::{account::AccountJob, JobRecord, States},
+};
+
+use super::Result;
+
+#[derive(Deserialize)]
+pub struct CancelReq {
+    id: u64,
+}
+
+pub async fn jobs(State(state): State<Arc<States>>) -> Json<Vec<JobInfo>> {
+    Json(state.scheduler.jobs())
+}
+
+pub async fn cancel_job(
+    State(state): State<Arc<States>>,
+    Query(query): Query<CancelReq>,
+) -> Result<Json<JobInfo>> {
+    let job = state
+        .scheduler
+        .jobs()
+        .into_iter()
+        .find(|e| e.id == query.id)
+        .ok_or_else(|| anyhow!("job {} does not exist", query.id))?;
+    state.scheduler.cancel(query.id);
+    Ok(Json(job))
+}
+
+// account jobs carry their account, check_library the series it ran for
+#[derive(Serialize)]
+pub struct JobHistory {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    series_id: Option<i64>,
+    job: &'static str,
+    #[serde(flatten)]
+    record: JobRecord,
+}
+
+pub async fn job_history(State(state): State<Arc<States>>) -> Json<Vec<JobHistory>> {
+    let accounts = state.job_history.iter().map(|e| JobHistory {
+        account: Some(e.key().0),
+        series_id: None,
+        job: e.key().1.name(),
+        record: e.value().clone(),
+    });
+    let library = state.library_history.iter().map(|e| JobHistory {
+        account: None,
+        series_id: Some(*e.key()),
+        job: "check_library",
+        record: e.value().clone(),
+    });
+    let mut list = accounts.chain(library).collect::<Vec<_>>();
+    list.sort_by_key(|e| (e.account.is_none(), e.account, e.job, e.series_id));
+    Json(list)
+}
+
+#[derive(Deserialize)]
+pub struct RunReq {
+    job: AccountJob,
+    account: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct RunRes {
+    account: i64,
+    job: AccountJob,
+    error: Option<String>,
+}
+
+pub async fn run_job(
+    State(state): State<Arc<States>>,
+    Query(query): Query<RunReq>,
+) -> Result<Json<Vec<RunRes>>> {
+    let accounts = if let Some(account) = query.account {
+        state.get_acc(account)?;
+        vec![account]
+    } else {
+        let mut accounts = state.accounts.iter().map(|e| *e.key()).collect::<Vec<_>>();
+        accounts.sort();
+        accounts
+    };
+    let handles = accounts
+        .into_iter()
+        .map(|account| (account, spawn(state.clone().run_job(account, query.job))))
+        .collect::<Vec<_>>();
+    let mut list = Vec::new();
+    for (account, handle) in handles {
+        list.push(RunRes {
+            account,
+            job: query.job,
+            error: handle.await?.err().map(|e| e.to_string()),
+        });
+    }
+    Ok(Json(list))
+}
@@ -0,0 +1,126 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::states::{
+    auth::{Quota, Scope, TicketUse},
+    States,
+};
+
+use super::{HttpError, Result};
+
+#[derive(Serialize)]
+pub struct KeyRes {
+    name: String,
+    scope: Scope,
+    created_at: i64,
+    quota: Option<Quota>,
+    managed: bool,
+    tickets_used: usize,
+}
+
+// tokens are only ever shown once, when the key is created
+pub async fn keys(State(state): State<Arc<States>>) -> Json<Vec<KeyRes>> {
+    let configured = state
+        .config
+        .api_keys
+        .values()
+        .map(|e| (e.clone(), false))
+        .collect::<Vec<_>>();
+    let managed = state.api_keys.iter().map(|e| (e.clone(), true));
+    let mut list = configured
+        .into_iter()
+        .chain(managed)
+        .map(|(key, managed)| KeyRes {
+            tickets_used: state.ticket_uses.get(&key.name).map_or(0, |e| e.uses.len()),
+            name: key.name,
+            scope: key.scope,
+            created_at: key.created_at,
+            quota: key.quota,
+            managed,
+        })
+        .collect::<Vec<_>>();
+    list.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(list)
+}
+
+#[derive(Deserialize)]
+pub struct CreateKeyReq {
+    name: String,
+    scope: Scope,
+    tickets_per_day: Option<usize>,
+    tickets_per_series: Option<usize>,
+    requests_per_minute: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct CreateKeyRes {
+    name: String,
+    scope: Scope,
+    token: String,
+}
+
+pub async fn create_key(
+    State(state): State<Arc<States>>,
+    Query(query): Query<CreateKeyReq>,
+) -> Result<Json<CreateKeyRes>> {
+    let limits = [
+        query.tickets_per_day,
+        query.tickets_per_series,
+        query.requests_per_minute,
+    ];
+    // without any limit given the key uses the quota in config.json
+    let quota = limits.iter().any(Option::is_some).then_some(Quota {
+        tickets_per_day: query.tickets_per_day,
+        tickets_per_series: query.tickets_per_series,
+        requests_per_minute: query.requests_per_minute,
+    });
+    let token = state
+        .create_key(query.name.clone(), query.scope, quota)
+        .map_err(|e| HttpError::new(StatusCode::BAD_REQUEST, e))?;
+    Ok(Json(CreateKeyRes {
+        name: query.name,
+        scope: query.scope,
+        token,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct KeyReq {
+    name: String,
+}
+
+pub async fn revoke_key(
+    State(state): State<Arc<States>>,
+    Query(query): Query<KeyReq>,
+) -> Result<Json<KeyRes>> {
+    let key = state
+        .revoke_key(&query.name)
+        .map_err(|e| HttpError::new(StatusCode::BAD_REQUEST, e))?;
+    Ok(Json(KeyRes {
+        tickets_used: state.ticket_uses.get(&key.name).map_or(0, |e| e.uses.len()),
+        name: key.name,
+        scope: key.scope,
+        created_at: key.created_at,
+        quota: key.quota,
+        managed: true,
+    }))
+}
+
+pub async fn key_tickets(
+    State(state): State<Arc<States>>,
+    Query(query): Query<KeyReq>,
+) -> Json<Vec<TicketUse>> {
+    Json(
+        state
+            .ticket_uses
+            .get(&query.name)
+            .map(|e| e.uses.clone())
+            .unwrap_or_default(),
+    )
+}
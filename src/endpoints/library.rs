@@ -0,0 +1,146 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    Extension, Json,
+};
+use log::warn;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::states::{
+    auth::{Caller, Scope},
+    library::Subscription,
+    series::Series,
+    States,
+};
+
+use super::{HttpError, Result};
+
+// titles come from the stored series meta, which subscribing fetches
+#[derive(Serialize, ToSchema)]
+pub struct LibraryItem {
+    series_id: i64,
+    title: Option<String>,
+    subscribed_at: i64,
+    auto_unlock: bool,
+    episode_count: usize,
+    checked_at: i64,
+    // episodes found after subscribing and not marked as read, oldest first
+    new_episodes: usize,
+    new_singles: Vec<i64>,
+}
+
+fn library_item(
+    states: &States,
+    caller: &Caller,
+    series_id: i64,
+    subscription: Subscription,
+) -> LibraryItem {
+    let new_singles = states.unread_new(caller, series_id);
+    let series = states.serieses.get(&series_id);
+    LibraryItem {
+        series_id,
+        title: series
+            .as_ref()
+            .and_then(|e| e.meta.as_ref().map(|e| e.title.clone())),
+        subscribed_at: subscription.subscribed_at,
+        auto_unlock: subscription.auto_unlock,
+        episode_count: series.as_ref().map_or(0, |e| e.episodes.len()),
+        checked_at: series.as_ref().map_or(0, |e| e.episodes_checked_at),
+        new_episodes: new_singles.len(),
+        new_singles,
+    }
+}
+
+// series with unread new episodes come first
+#[utoipa::path(
+    get,
+    path = "/library",
+    responses((status = 200, body = [LibraryItem]))
+)]
+pub async fn library(
+    State(state): State<Arc<States>>,
+    Extension(caller): Extension<Caller>,
+) -> Json<Vec<LibraryItem>> {
+    let mut list = state
+        .subscriptions_of(&caller)
+        .into_iter()
+        .map(|(series_id, e)| library_item(&state, &caller, series_id, e))
+        .collect::<Vec<_>>();
+    list.sort_by_key(|e| std::cmp::Reverse(e.new_episodes));
+    Json(list)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SubscribeReq {
+    series_id: i64,
+    #[serde(default)]
+    auto_unlock: bool,
+}
+
+// the episode list is synced first, so only episodes published afterwards
+// count as new
+#[utoipa::path(
+    post,
+    path = "/library/subscribe",
+    params(SubscribeReq),
+    responses(
+        (status = 200, body = LibraryItem),
+        (status = 403, description = "auto_unlock was asked for by a key that may not spend tickets")
+    )
+)]
+pub async fn subscribe(
+    State(state): State<Arc<States>>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<SubscribeReq>,
+) -> Result<Json<LibraryItem>> {
+    if query.auto_unlock && caller.scope < Scope::Tickets {
+        Err(HttpError::new(
+            StatusCode::FORBIDDEN,
+            format!("api key {} may not spend tickets", caller.name),
+        ))?
+    }
+    Series::sync_episodes(&state, query.series_id, false).await?;
+    if let Err(e) = Series::sync_meta(&state, query.series_id, false).await {
+        warn!("failed to fetch meta of series {}: {e}", query.series_id);
+    }
+    let subscription = state.subscribe(&caller, query.series_id, query.auto_unlock);
+    Ok(Json(library_item(
+        &state,
+        &caller,
+        query.series_id,
+        subscription,
+    )))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UnsubscribeReq {
+    series_id: i64,
+}
+
+#[utoipa::path(
+    post,
+    path = "/library/unsubscribe",
+    params(UnsubscribeReq),
+    responses(
+        (status = 200, body = Subscription),
+        (status = 404, description = "the series is not subscribed to")
+    )
+)]
+pub async fn unsubscribe(
+    State(state): State<Arc<States>>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<UnsubscribeReq>,
+) -> Result<Json<Subscription>> {
+    let subscription = state.unsubscribe(&caller, query.series_id).ok_or_else(|| {
+        HttpError::new(
+            StatusCode::NOT_FOUND,
+            format!("series {} is not subscribed to", query.series_id),
+        )
+    })?;
+    Ok(Json(subscription))
+}
@@ -0,0 +1,14 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{header::CONTENT_TYPE, HeaderMap},
+};
+
+use crate::states::States;
+
+pub async fn metrics(State(state): State<Arc<States>>) -> (HeaderMap, String) {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+    (headers, crate::metrics::render(&state))
+}
@@ -3,15 +3,58 @@ use std::fmt::{Debug, Formatter};
 use axum::response::{IntoResponse, Response};
 use reqwest::StatusCode;
 
+use crate::{graphql::GraphQlError, states::auth::QuotaExceeded, util::now};
+
+pub mod accounts;
+pub mod cache;
+pub mod episodes;
+pub mod jobs;
+pub mod keys;
+pub mod library;
+pub mod metrics;
+pub mod openapi;
+pub mod operations;
+pub mod progress;
 pub mod resource;
 pub mod search;
 pub mod series;
 pub mod single;
+pub mod usage;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 pub struct Error(anyhow::Error);
 
+// for failures that are the caller's fault rather than ours or upstream's
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl HttpError {
+    pub fn new(status: StatusCode, message: impl ToString) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl IntoResponse for HttpError {
+    fn into_response(self) -> Response {
+        (self.status, self.message).into_response()
+    }
+}
+
 impl<E: Into<anyhow::Error>> From<E> for Error {
     fn from(value: E) -> Self {
         Self(value.into())
@@ -24,12 +67,33 @@ impl Debug for Error {
     }
 }
 
+impl IntoResponse for QuotaExceeded {
+    fn into_response(self) -> Response {
+        let retry_after = (self.reset_at - now()).max(0).to_string();
+        let headers = [
+            ("retry-after", retry_after),
+            ("x-ratelimit-reset", self.reset_at.to_string()),
+        ];
+        (StatusCode::TOO_MANY_REQUESTS, headers, self.to_string()).into_response()
+    }
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        let error = (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        );
+        let error = match self.0.downcast::<HttpError>() {
+            Ok(error) => return error.into_response(),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<QuotaExceeded>() {
+            Ok(error) => return error.into_response(),
+            Err(error) => error,
+        };
+        let status = if error.downcast_ref::<GraphQlError>().is_some() {
+            StatusCode::BAD_GATEWAY
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        let error = (status, format!("Something went wrong: {error}"));
         error.into_response()
     }
 }
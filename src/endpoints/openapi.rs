@@ -0,0 +1,86 @@
+use axum::Json;
+use utoipa::{
+    openapi::{
+        self,
+        security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    },
+    Modify, OpenApi,
+};
+
+use crate::states::{
+    auth::{Scope, Usage},
+    library::Subscription,
+    progress::{ReadMark, SeriesProgress},
+    series::{Episode, Image, Single, Viewer, KHTML},
+};
+
+use super::{episodes, library, progress, search, series, single, usage};
+
+// only the endpoints meant for clients, the admin ones are left out
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        episodes::episodes,
+        library::library,
+        library::subscribe,
+        library::unsubscribe,
+        progress::progress,
+        progress::mark,
+        progress::continue_reading,
+        search::search,
+        search::suggest,
+        series::series,
+        series::series_meta,
+        single::single,
+        usage::usage
+    ),
+    components(schemas(
+        episodes::EpisodesRes,
+        library::LibraryItem,
+        progress::ContinueItem,
+        search::Category,
+        search::SearchRes,
+        search::SearchItem,
+        search::Suggestion,
+        series::SeriesRes,
+        series::SeriesMeta,
+        series::Rating,
+        series::SeriesItem,
+        series::Sort,
+        single::SingleRes,
+        usage::UsageRes,
+        Episode,
+        Image,
+        ReadMark,
+        KHTML,
+        Scope,
+        SeriesProgress,
+        Single,
+        Subscription,
+        Usage,
+        Viewer,
+    )),
+    modifiers(&Security),
+    security(("bearer" = []), ("api_key" = []))
+)]
+struct ApiDoc;
+
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("x-api-key"))),
+        );
+    }
+}
+
+pub async fn openapi() -> Json<openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
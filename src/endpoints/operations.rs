@@ -0,0 +1,7 @@
+use axum::Json;
+
+use crate::graphql::{documents, Document};
+
+pub async fn operations() -> Json<Vec<&'static Document>> {
+    Json(documents())
+}
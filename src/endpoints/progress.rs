@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::states::{auth::Caller, progress::SeriesProgress, States};
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ProgressReq {
+    series_id: i64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/progress",
+    params(ProgressReq),
+    responses((status = 200, body = SeriesProgress))
+)]
+pub async fn progress(
+    State(state): State<Arc<States>>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<ProgressReq>,
+) -> Json<SeriesProgress> {
+    Json(state.series_progress(&caller, query.series_id))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MarkReq {
+    series_id: i64,
+    single_id: i64,
+    read: bool,
+}
+
+#[utoipa::path(
+    post,
+    path = "/progress/mark",
+    params(MarkReq),
+    responses((status = 200, body = SeriesProgress))
+)]
+pub async fn mark(
+    State(state): State<Arc<States>>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<MarkReq>,
+) -> Json<SeriesProgress> {
+    state.mark_read(&caller, query.series_id, query.single_id, query.read);
+    Json(state.series_progress(&caller, query.series_id))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ContinueReq {
+    #[serde(default = "limit")]
+    limit: usize,
+}
+
+fn limit() -> usize {
+    20
+}
+
+// titles and the next single come from what is already stored locally, so
+// they are missing for anything that was never fetched
+#[derive(Serialize, ToSchema)]
+pub struct ContinueItem {
+    series_id: i64,
+    series_title: Option<String>,
+    single_id: i64,
+    single_title: Option<String>,
+    next: Option<i64>,
+    read_at: i64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/progress/continue",
+    params(ContinueReq),
+    responses((status = 200, body = [ContinueItem]))
+)]
+pub async fn continue_reading(
+    State(state): State<Arc<States>>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<ContinueReq>,
+) -> Json<Vec<ContinueItem>> {
+    let mut list = state
+        .progress
+        .get(&caller.name)
+        .map(|e| {
+            e.iter()
+                .filter_map(|(series_id, e)| Some((*series_id, e.last_single?, e.last_read_at)))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    list.sort_by_key(|e| -e.2);
+    list.truncate(query.limit);
+    let list = list
+        .into_iter()
+        .map(|(series_id, single_id, read_at)| {
+            let series = state.serieses.get(&series_id);
+            let single = series
+                .as_ref()
+                .and_then(|e| e.single_map.get(&single_id).map(|e| e.clone()));
+            ContinueItem {
+                series_id,
+                series_title: series
+                    .as_ref()
+                    .and_then(|e| e.meta.as_ref().map(|e| e.title.clone())),
+                single_id,
+                single_title: single.as_ref().map(|e| e.title.clone()),
+                next: single.and_then(|e| e.next),
+                read_at,
+            }
+        })
+        .collect();
+    Json(list)
+}
@@ -6,23 +6,33 @@ use axum::{
     response::{IntoResponse, Response},
 };
 
-use crate::states::States;
+use crate::{metrics::RESOURCE_BYTES, states::States};
 
 use super::Result;
 
 pub async fn resource(State(state): State<Arc<States>>, oguri: OriginalUri) -> Result<Response> {
-    let url = "https://dn-img-page.kakao.com".to_string() + oguri.to_string().as_str();
+    // the api key may be passed as a query parameter and must not leak upstream
+    let mut url = "https://dn-img-page.kakao.com".to_string() + oguri.path();
+    let query = oguri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|e| !e.is_empty() && !e.starts_with("api_key="))
+        .collect::<Vec<_>>();
+    if !query.is_empty() {
+        url = url + "?" + &query.join("&");
+    }
     let res = state.client.get(url).send().await?;
-    let res = (
-        res.status(),
-        res.headers()
-            .into_iter()
-            .filter_map(|(key, val)| match key.as_str() {
-                "content-type" => Some((key.clone(), val.clone())),
-                _ => None,
-            })
-            .collect::<HeaderMap>(),
-        res.bytes().await?,
-    );
-    Ok(res.into_response())
+    let status = res.status();
+    let headers = res
+        .headers()
+        .into_iter()
+        .filter_map(|(key, val)| match key.as_str() {
+            "content-type" => Some((key.clone(), val.clone())),
+            _ => None,
+        })
+        .collect::<HeaderMap>();
+    let bytes = res.bytes().await?;
+    RESOURCE_BYTES.inc_by(&[], bytes.len() as u64);
+    Ok((status, headers, bytes).into_response())
 }
@@ -5,6 +5,7 @@ use axum::{
     Json,
 };
 use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
 use vitis_be_macros::macroql;
 
 use crate::{states::States, util::get_param};
@@ -13,26 +14,72 @@ use self::search_keyword::{vars::SearchKeywordInput, Vars};
 
 use super::Result;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct SearchReq {
     keyword: String,
     #[serde(default)]
     page: i32,
+    category: Option<Category>,
+    completed: Option<bool>,
+    wait_free: Option<bool>,
+    free: Option<bool>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    Webtoon,
+    Novel,
+    Other,
+}
+
+impl Category {
+    fn from_upstream(category_type: &str) -> Self {
+        match category_type {
+            "Webtoon" => Category::Webtoon,
+            "Webnovel" => Category::Novel,
+            _ => Category::Other,
+        }
+    }
+}
+
+// with filters set, upstream pages are read until the list is as long as an
+// unfiltered page or MAX_SEARCH_PAGES were read, the list can then still come
+// back short, so the next request should ask for `next_page`
+#[derive(Serialize, ToSchema)]
 pub struct SearchRes {
-    list: Vec<Series>,
+    list: Vec<SearchItem>,
     more: bool,
+    next_page: i32,
 }
 
-#[derive(Serialize)]
-pub struct Series {
+const MAX_SEARCH_PAGES: i32 = 5;
+
+#[derive(Serialize, ToSchema)]
+pub struct SearchItem {
     series_id: i64,
     cover: String,
     title: String,
-    row_1: String,
-    row_2: String,
+    category: Option<Category>,
+    genre: Option<String>,
+    authors: Option<String>,
+    age_grade: Option<String>,
+    completed: Option<bool>,
+    wait_free: Option<bool>,
+    free: Option<bool>,
+    tags: Vec<String>,
+    meta: Vec<String>,
+}
+
+impl SearchItem {
+    fn matches(&self, query: &SearchReq) -> bool {
+        let category = query.category.is_none_or(|e| self.category == Some(e));
+        let completed = query.completed.is_none_or(|e| self.completed == Some(e));
+        let wait_free = query.wait_free.is_none_or(|e| self.wait_free == Some(e));
+        let free = query.free.is_none_or(|e| self.free == Some(e));
+        category && completed && wait_free && free
+    }
 }
 
 macroql! {
@@ -50,37 +97,163 @@ macroql! {
                     row3: {
                         metaList: [String]
                     },
-                    scheme: String
+                    scheme: String,
+                    series: ? {
+                        categoryType: String,
+                        subcategory: String?,
+                        authors: String?,
+                        ageGrade: String?,
+                        onIssue: String?,
+                        isWaitfree: Boolean,
+                        isAllFree: Boolean
+                    }
             },
             isEnd: Boolean
         }
     }
 }
 
-pub async fn search(
-    State(state): State<Arc<States>>,
-    Query(query): Query<SearchReq>,
-) -> Result<Json<SearchRes>> {
+// one upstream page, returned with whether it was the last
+async fn search_page(
+    state: &States,
+    query: &SearchReq,
+    page: i32,
+) -> Result<(Vec<SearchItem>, bool)> {
     let sels = search_keyword(
         state.client.clone(),
         Vars {
             search_keyword_input: SearchKeywordInput {
-                keyword: query.keyword,
-                page: query.page,
+                keyword: query.keyword.clone(),
+                page,
             },
         },
     )
     .await?;
     let mut list = Vec::new();
     for item in sels.search_keyword.list {
-        list.push(Series {
+        let series = item.series;
+        list.push(SearchItem {
             series_id: get_param(&item.scheme, "series_id")?.parse()?,
             cover: get_param(&item.thumbnail, "kid")?,
             title: item.row_1,
-            row_1: item.row_2.join(" · "),
-            row_2: item.row_3.meta_list.join(" · "),
+            category: series
+                .as_ref()
+                .map(|e| Category::from_upstream(&e.category_type)),
+            genre: series.as_ref().and_then(|e| e.subcategory.clone()),
+            authors: series.as_ref().and_then(|e| e.authors.clone()),
+            age_grade: series.as_ref().and_then(|e| e.age_grade.clone()),
+            completed: series
+                .as_ref()
+                .and_then(|e| e.on_issue.as_ref())
+                .map(|e| e == "End"),
+            wait_free: series.as_ref().map(|e| e.is_waitfree),
+            free: series.as_ref().map(|e| e.is_all_free),
+            tags: item.row_2,
+            meta: item.row_3.meta_list,
+        });
+    }
+    Ok((list, sels.search_keyword.is_end))
+}
+
+#[utoipa::path(
+    get,
+    path = "/search",
+    params(SearchReq),
+    responses((status = 200, body = SearchRes))
+)]
+pub async fn search(
+    State(state): State<Arc<States>>,
+    Query(query): Query<SearchReq>,
+) -> Result<Json<SearchRes>> {
+    let mut list = Vec::new();
+    let mut page = query.page;
+    let mut page_size = None;
+    let mut more = true;
+    while more && page < query.page + MAX_SEARCH_PAGES {
+        let (items, is_end) = search_page(&state, &query, page).await?;
+        page += 1;
+        more = !is_end;
+        let page_size = *page_size.get_or_insert(items.len());
+        list.extend(items.into_iter().filter(|e| e.matches(&query)));
+        if list.len() >= page_size {
+            break;
+        }
+    }
+    Ok(Json(SearchRes {
+        list,
+        more,
+        next_page: page,
+    }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SuggestReq {
+    keyword: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Suggestion {
+    series_id: i64,
+    title: String,
+    category: Option<Category>,
+}
+
+const MAX_SUGGESTIONS: usize = 10;
+
+// a smaller query than search_keyword, so it can be given its own cache policy
+macroql! {
+    query search_suggest (
+        searchKeywordInput: SearchKeywordInput {
+            keyword: String,
+            page: Int
+        }
+    ) {
+        searchKeyword(searchKeywordInput) {
+            list: [] {
+                    row1: String,
+                    scheme: String,
+                    series: ? {
+                        categoryType: String
+                    }
+            }
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/search/suggest",
+    params(SuggestReq),
+    responses((status = 200, body = [Suggestion]))
+)]
+pub async fn suggest(
+    State(state): State<Arc<States>>,
+    Query(query): Query<SuggestReq>,
+) -> Result<Json<Vec<Suggestion>>> {
+    let keyword = query.keyword.trim();
+    if keyword.is_empty() {
+        return Ok(Json(Vec::new()));
+    }
+    let sels = search_suggest(
+        state.client.clone(),
+        search_suggest::Vars {
+            search_keyword_input: search_suggest::vars::SearchKeywordInput {
+                keyword: keyword.to_string(),
+                page: 0,
+            },
+        },
+    )
+    .await?;
+    let mut list = Vec::new();
+    for item in sels.search_keyword.list.into_iter().take(MAX_SUGGESTIONS) {
+        list.push(Suggestion {
+            series_id: get_param(&item.scheme, "series_id")?.parse()?,
+            title: item.row_1,
+            category: item
+                .series
+                .map(|e| Category::from_upstream(&e.category_type)),
         });
     }
-    let more = !sels.search_keyword.is_end;
-    Ok(Json(SearchRes { list, more }))
+    Ok(Json(list))
 }
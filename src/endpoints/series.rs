@@ -1,63 +1,149 @@
-use std::sync::Arc;
+use std::{
+    fmt::{Display, Formatter},
+    sync::Arc,
+};
 
 use axum::{
     extract::{Query, State},
     Json,
 };
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
 use vitis_be_macros::macroql;
 
-use crate::{states::States, util::get_param};
+use crate::{
+    graphql::Batch,
+    states::{
+        series::{series_meta as meta_query, Meta, Series},
+        States,
+    },
+    util::get_param,
+};
 
-use super::Result;
+use super::{HttpError, Result};
 
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct SeriesReq {
     series_id: i64,
-    #[serde(default)]
-    page: i32,
+    // the next cursor of the previous page, meta is only sent without one
+    cursor: Option<String>,
+    // starts the page at this episode, counted from the first one, so the
+    // page is always in ascending order
+    episode: Option<i64>,
     #[serde(default)]
     sort: Sort,
+    // replaced by cursor, still read so that a client paging by it is told
+    // so instead of getting the first page over and over
+    page: Option<i64>,
+}
+
+// pages are asked for with `first` items after `after`, an offset into the
+// list, so an episode can be jumped to directly, the page number this replaced
+// asked for `last` with both values growing on every page
+const PAGE_SIZE: i32 = 25;
+
+// cursors carry the sort they were made with, so following one never flips
+// the order of the pages
+fn encode_cursor(sort: Sort, after: &str) -> String {
+    match sort {
+        Sort::Dsc => format!("d{after}"),
+        Sort::Asc => format!("a{after}"),
+    }
+}
+
+fn decode_cursor(cursor: &str) -> Result<(Sort, String)> {
+    if let Some(after) = cursor.strip_prefix('d') {
+        Ok((Sort::Dsc, after.to_string()))
+    } else if let Some(after) = cursor.strip_prefix('a') {
+        Ok((Sort::Asc, after.to_string()))
+    } else {
+        Err(HttpError::new(StatusCode::BAD_REQUEST, "invalid cursor"))?
+    }
 }
 
-#[derive(Default, Deserialize)]
+#[derive(Default, Deserialize, ToSchema, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
-enum Sort {
+pub enum Sort {
     #[default]
     Dsc,
     Asc,
 }
 
-impl ToString for Sort {
-    fn to_string(&self) -> String {
+impl Display for Sort {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match &self {
             Sort::Dsc => "",
             Sort::Asc => "asc",
         }
-        .to_string()
+        .fmt(f)
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct SeriesRes {
-    meta: Option<Series>,
-    list: Vec<Single>,
+    meta: Option<SeriesMeta>,
+    list: Vec<SeriesItem>,
     more: bool,
+    next: Option<String>,
 }
 
-#[derive(Serialize)]
-struct Series {
+#[derive(Serialize, ToSchema)]
+pub struct SeriesMeta {
     cover: String,
     title: String,
     pub_period: Option<String>,
     view_count: i64,
-    rating: f64,
+    rating: Rating,
     author: String,
     description: String,
+    genre: Option<String>,
+    age_rating: Option<String>,
+    wait_free_interval: Option<i64>,
+    episode_count: Option<i64>,
+    fetched_at: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Rating {
+    // null until someone rates the series
+    average: Option<f64>,
+    count: i64,
+    sum: i64,
 }
 
-#[derive(Serialize)]
-struct Single {
+impl Rating {
+    fn new(count: i64, sum: i64) -> Self {
+        Self {
+            average: (count > 0).then(|| sum as f64 / count as f64),
+            count,
+            sum,
+        }
+    }
+}
+
+impl From<Meta> for SeriesMeta {
+    fn from(meta: Meta) -> Self {
+        Self {
+            cover: meta.cover,
+            title: meta.title,
+            pub_period: meta.pub_period,
+            view_count: meta.view_count,
+            rating: Rating::new(meta.rating_count, meta.rating_sum),
+            author: meta.author,
+            description: meta.description,
+            genre: meta.genre,
+            age_rating: meta.age_rating,
+            wait_free_interval: meta.wait_free_interval,
+            episode_count: meta.episode_count,
+            fetched_at: meta.fetched_at,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SeriesItem {
     single_id: i64,
     cover: String,
     title: String,
@@ -66,29 +152,16 @@ struct Single {
 }
 
 macroql! {
-    query series_full (
+    query single_list (
         sortType: String,
         seriesId: Long,
+        after: String?,
+        first: Int
     ) {
-        contentHomeOverview(seriesId) {
-            content: {
-                thumbnail: String,
-                title: String,
-                authors: String,
-                pubPeriod: String?,
-                serviceProperty: {
-                    viewCount: Long,
-                    ratingCount: Long,
-                    ratingSum: Long
-                }
-            }
-        },
-        contentHomeAbout(seriesId) {
-            description: String,
-        },
-        contentHomeProductList(sortType, seriesId) {
+        contentHomeProductList(sortType, seriesId, after, first) {
             pageInfo {
-                hasNextPage: Boolean
+                hasNextPage: Boolean,
+                endCursor: String?
             },
             edges: [] {
                 node: {
@@ -105,105 +178,141 @@ macroql! {
     }
 }
 
-macroql! {
-    query single_list (
-        sortType: String,
-        seriesId: Long,
-        after: String,
-        last: Int
-    ) {
-        contentHomeProductList(sortType, seriesId, after, last) {
-            pageInfo {
-                hasNextPage: Boolean
-            },
-            edges: [] {
-                node: {
-                    thumbnail: String,
-                    row1: {
-                        title: String
-                    },
-                    row2: [String],
-                    row3: String?,
-                    scheme: String
-                }
-            }
-        }
-    }
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MetaReq {
+    series_id: i64,
+    // skips the local copy even when it is still fresh
+    #[serde(default)]
+    refresh: bool,
 }
 
+#[utoipa::path(
+    get,
+    path = "/series/meta",
+    params(MetaReq),
+    responses((status = 200, body = SeriesMeta))
+)]
+pub async fn series_meta(
+    State(state): State<Arc<States>>,
+    Query(query): Query<MetaReq>,
+) -> Result<Json<SeriesMeta>> {
+    let meta = Series::sync_meta(&state, query.series_id, query.refresh).await?;
+    Ok(Json(meta.into()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/series",
+    params(SeriesReq),
+    responses(
+        (status = 200, body = SeriesRes),
+        (status = 400, description = "page was given, or episode is not positive, or was given with cursor")
+    )
+)]
 pub async fn series(
     State(state): State<Arc<States>>,
     Query(query): Query<SeriesReq>,
 ) -> Result<Json<SeriesRes>> {
-    macro_rules! list {
-        ($sels:ident) => {{
-            let mut list = Vec::new();
-            for item in $sels.content_home_product_list.edges {
-                list.push(Single {
-                    single_id: get_param(&item.node.scheme, "product_id")?.parse()?,
-                    cover: get_param(&item.node.thumbnail, "kid")?.parse()?,
-                    title: item.node.row_1.title,
-                    row_1: item.node.row_2.join(" · "),
-                    row_2: item.node.row_3,
-                })
-            }
-            list
-        }};
+    if query.page.is_some() {
+        Err(HttpError::new(
+            StatusCode::BAD_REQUEST,
+            "page is no longer supported, pass next as cursor instead",
+        ))?
     }
-    if query.page == 0 {
-        let sels = series_full(
-            state.client.clone(),
-            series_full::Vars {
-                sort_type: query.sort.to_string(),
-                series_id: query.series_id,
-            },
-        )
-        .await?;
-        Ok(Json(SeriesRes {
-            meta: Some(Series {
-                cover: get_param(&sels.content_home_overview.content.thumbnail, "kid")?,
-                title: sels.content_home_overview.content.title,
-                pub_period: sels.content_home_overview.content.pub_period,
-                view_count: sels
-                    .content_home_overview
-                    .content
-                    .service_property
-                    .view_count,
-                rating: {
-                    let num = sels
-                        .content_home_overview
-                        .content
-                        .service_property
-                        .rating_sum;
-                    let den = sels
-                        .content_home_overview
-                        .content
-                        .service_property
-                        .rating_count;
-                    num as f64 / den as f64
-                },
-                author: sels.content_home_overview.content.authors,
-                description: sels.content_home_about.description,
-            }),
-            list: list!(sels),
-            more: sels.content_home_product_list.page_info.has_next_page,
-        }))
+    let first_page = query.cursor.is_none();
+    let (after, sort) = match (query.cursor, query.episode) {
+        (Some(_), Some(_)) => Err(HttpError::new(
+            StatusCode::BAD_REQUEST,
+            "cursor and episode can not be used together",
+        ))?,
+        (None, Some(episode)) if episode < 1 => Err(HttpError::new(
+            StatusCode::BAD_REQUEST,
+            "episode is counted from 1",
+        ))?,
+        // the first episode is the start of the list, which has no offset
+        (None, Some(episode)) => ((episode > 1).then(|| (episode - 1).to_string()), Sort::Asc),
+        (Some(cursor), None) => {
+            let (sort, after) = decode_cursor(&cursor)?;
+            (Some(after), sort)
+        }
+        (None, None) => (None, query.sort),
+    };
+    let vars = single_list::Vars {
+        sort_type: sort.to_string(),
+        series_id: query.series_id,
+        after,
+        first: PAGE_SIZE,
+    };
+    let fresh = if first_page {
+        Series::fresh_meta(&state, query.series_id, false)
     } else {
-        let sels = single_list(
-            state.client.clone(),
-            single_list::Vars {
-                sort_type: query.sort.to_string(),
-                series_id: query.series_id,
-                // sums of arithmetic series ^^7
-                after: ((query.page + 1) * query.page / 2 * 25).to_string(),
-                last: (query.page + 1) * 25,
-            },
-        )
-        .await?;
-        Ok(Json(SeriesRes {
-            meta: None,
-            list: list!(sels),
-            more: sels.content_home_product_list.page_info.has_next_page,
-        }))
+        None
+    };
+    // a first page without a fresh meta needs both, they do not depend on each
+    // other so they can share one request
+    let (meta, sels) = if first_page && fresh.is_none() {
+        let mut batch = Batch::new(state.client.clone(), state.config.batch_queries);
+        let meta = batch.push(&meta_query::OPERATION, Series::meta_vars(query.series_id))?;
+        let list = batch.push(&single_list::OPERATION, vars)?;
+        let mut responses = batch.send().await?;
+        let meta = Series::store_meta(&state, query.series_id, responses.take(meta))?;
+        (Some(meta.into()), responses.take(list)?)
+    } else {
+        let sels = single_list(state.client.clone(), vars).await?;
+        (fresh.map(Into::into), sels)
+    };
+    let page_info = &sels.content_home_product_list.page_info;
+    let more = page_info.has_next_page;
+    let next = page_info
+        .end_cursor
+        .as_deref()
+        .filter(|_| more)
+        .map(|e| encode_cursor(sort, e));
+    let mut list = Vec::new();
+    for item in sels.content_home_product_list.edges {
+        list.push(SeriesItem {
+            single_id: get_param(&item.node.scheme, "product_id")?.parse()?,
+            cover: get_param(&item.node.thumbnail, "kid")?.parse()?,
+            title: item.node.row_1.title,
+            row_1: item.node.row_2.join(" · "),
+            row_2: item.node.row_3,
+        })
+    }
+    Ok(Json(SeriesRes {
+        meta,
+        list,
+        more,
+        next,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_keeps_its_sort() {
+        let (sort, after) = decode_cursor(&encode_cursor(Sort::Dsc, "25")).unwrap();
+        assert!(matches!(sort, Sort::Dsc));
+        assert_eq!(after, "25");
+        let (sort, after) = decode_cursor(&encode_cursor(Sort::Asc, "50")).unwrap();
+        assert!(matches!(sort, Sort::Asc));
+        assert_eq!(after, "50");
+    }
+
+    #[test]
+    fn cursor_without_sort_is_rejected() {
+        assert!(decode_cursor("25").is_err());
+        assert!(decode_cursor("").is_err());
+        assert!(decode_cursor("x25").is_err());
+    }
+
+    #[test]
+    fn cursor_keeps_any_upstream_value() {
+        let (_, after) = decode_cursor(&encode_cursor(Sort::Asc, "")).unwrap();
+        assert_eq!(after, "");
+        let (_, after) = decode_cursor(&encode_cursor(Sort::Dsc, "d7")).unwrap();
+        assert_eq!(after, "d7");
     }
 }
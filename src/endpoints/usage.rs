@@ -0,0 +1,68 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    extract::{Query, State},
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    states::{
+        auth::{Caller, Scope, Usage},
+        States,
+    },
+    util::now,
+};
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UsageReq {
+    series_id: Option<i64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UsageRes {
+    key: String,
+    scope: Scope,
+    requests_per_minute: Usage,
+    tickets_per_day: Usage,
+    // every series used in the last day, plus the requested one
+    tickets_per_series: BTreeMap<i64, Usage>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/usage",
+    params(UsageReq),
+    responses((status = 200, body = UsageRes))
+)]
+pub async fn usage(
+    State(state): State<Arc<States>>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<UsageReq>,
+) -> Json<UsageRes> {
+    let since = now() - 86400;
+    let mut serieses = state
+        .ticket_uses
+        .get(&caller.name)
+        .map(|e| {
+            e.uses
+                .iter()
+                .filter(|e| e.at > since)
+                .map(|e| e.series_id)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    serieses.extend(query.series_id);
+    Json(UsageRes {
+        requests_per_minute: state.request_usage(&caller),
+        tickets_per_day: state.ticket_usage(&caller, None),
+        tickets_per_series: serieses
+            .into_iter()
+            .map(|e| (e, state.ticket_usage(&caller, Some(e))))
+            .collect(),
+        key: caller.name,
+        scope: caller.scope,
+    })
+}
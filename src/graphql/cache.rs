@@ -0,0 +1,180 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+use dashmap::DashMap;
+use reqwest::StatusCode;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    metrics::{header, labels, sample},
+    states::config::CachePolicy,
+    util::now,
+};
+
+pub struct Cache {
+    policy: CachePolicy,
+    entries: DashMap<String, Entry>,
+    last_sweep: AtomicI64,
+    hits: AtomicU64,
+    stale_hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Entry {
+    stored_at: i64,
+    status: StatusCode,
+    res: Value,
+}
+
+pub enum Lookup {
+    Fresh(StatusCode, Value),
+    Stale(StatusCode, Value),
+    Miss,
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    ttl: i64,
+    stale: i64,
+    entries: usize,
+    hits: u64,
+    stale_hits: u64,
+    misses: u64,
+}
+
+static CACHES: OnceLock<HashMap<String, Cache>> = OnceLock::new();
+
+pub fn init(policies: &HashMap<String, CachePolicy>) {
+    let caches = policies
+        .iter()
+        .map(|(name, policy)| {
+            let cache = Cache {
+                policy: *policy,
+                entries: DashMap::new(),
+                last_sweep: AtomicI64::new(now()),
+                hits: AtomicU64::new(0),
+                stale_hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+            };
+            (name.clone(), cache)
+        })
+        .collect();
+    let _ = CACHES.set(caches);
+}
+
+pub fn get(name: &str) -> Option<&'static Cache> {
+    CACHES.get()?.get(name)
+}
+
+pub fn stats() -> HashMap<String, CacheStats> {
+    CACHES
+        .get()
+        .into_iter()
+        .flatten()
+        .map(|(name, cache)| (name.clone(), cache.stats()))
+        .collect()
+}
+
+impl Cache {
+    pub fn lookup(&self, key: &str) -> Lookup {
+        if let Some(entry) = self.entries.get(key) {
+            let age = now() - entry.stored_at;
+            if age < self.policy.ttl {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Lookup::Fresh(entry.status, entry.res.clone());
+            }
+            if age < self.policy.ttl + self.policy.stale {
+                self.stale_hits.fetch_add(1, Ordering::Relaxed);
+                return Lookup::Stale(entry.status, entry.res.clone());
+            }
+        }
+        self.entries.remove_if(key, |_, e| self.expired(e));
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        Lookup::Miss
+    }
+
+    fn expired(&self, entry: &Entry) -> bool {
+        now() - entry.stored_at >= self.policy.ttl + self.policy.stale
+    }
+
+    // expired entries are dropped when looked up, the ones never asked for
+    // again by a sweep at most once per lifetime of an entry
+    fn sweep(&self) {
+        let last_sweep = self.last_sweep.load(Ordering::Relaxed);
+        if now() - last_sweep < self.policy.ttl + self.policy.stale {
+            return;
+        }
+        let swept = self.last_sweep.compare_exchange(
+            last_sweep,
+            now(),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        if swept.is_ok() {
+            self.entries.retain(|_, e| !self.expired(e));
+        }
+    }
+
+    // only clean responses are worth keeping, errors should be retried
+    pub fn store(&self, key: String, status: StatusCode, res: &Value) {
+        self.sweep();
+        if status.is_success() && res.get("errors").is_none() {
+            let entry = Entry {
+                stored_at: now(),
+                status,
+                res: res.clone(),
+            };
+            self.entries.insert(key, entry);
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            ttl: self.policy.ttl,
+            stale: self.policy.stale,
+            entries: self.entries.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            stale_hits: self.stale_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub fn render(out: &mut String) {
+    let mut caches = CACHES.get().into_iter().flatten().collect::<Vec<_>>();
+    caches.sort_by_key(|e| e.0);
+    let name = "graphql_cache_lookups_total";
+    header(
+        out,
+        name,
+        "counter",
+        "Response cache lookups by operation and result.",
+    );
+    for (operation, cache) in &caches {
+        for (result, count) in [
+            ("hit", &cache.hits),
+            ("stale", &cache.stale_hits),
+            ("miss", &cache.misses),
+        ] {
+            let labels = labels(&[("operation", operation), ("result", result)]);
+            sample(out, name, &labels, count.load(Ordering::Relaxed) as f64);
+        }
+    }
+    let name = "graphql_cache_entries";
+    header(
+        out,
+        name,
+        "gauge",
+        "Responses held in the cache by operation.",
+    );
+    for (operation, cache) in &caches {
+        let labels = labels(&[("operation", operation)]);
+        sample(out, name, &labels, cache.entries.len() as f64);
+    }
+}
@@ -0,0 +1,100 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use reqwest::StatusCode;
+
+use crate::metrics::{header, labels, sample, Histogram};
+
+#[derive(Default)]
+struct OperationMetrics {
+    latency: Histogram,
+    statuses: DashMap<u16, u64>,
+    failures: AtomicU64,
+    errors: AtomicU64,
+}
+
+static METRICS: OnceLock<DashMap<&'static str, OperationMetrics>> = OnceLock::new();
+
+fn metrics() -> &'static DashMap<&'static str, OperationMetrics> {
+    METRICS.get_or_init(DashMap::new)
+}
+
+// a missing status means the request never got a response
+pub fn record(name: &'static str, latency: Duration, status: Option<StatusCode>, errors: usize) {
+    let metrics = metrics().entry(name).or_default();
+    metrics.latency.observe(latency);
+    if let Some(status) = status {
+        *metrics.statuses.entry(status.as_u16()).or_default() += 1;
+    } else {
+        metrics.failures.fetch_add(1, Ordering::Relaxed);
+    }
+    metrics.errors.fetch_add(errors as u64, Ordering::Relaxed);
+}
+
+pub fn render(out: &mut String) {
+    let mut metrics = metrics().iter().collect::<Vec<_>>();
+    metrics.sort_by_key(|e| *e.key());
+    let name = "graphql_request_duration_seconds";
+    header(
+        out,
+        name,
+        "histogram",
+        "Latency of upstream GraphQL operations.",
+    );
+    for e in &metrics {
+        e.latency
+            .render(out, name, &labels(&[("operation", e.key())]));
+    }
+    let name = "graphql_requests_total";
+    header(
+        out,
+        name,
+        "counter",
+        "Upstream GraphQL responses by HTTP status.",
+    );
+    for e in &metrics {
+        let mut statuses = e
+            .statuses
+            .iter()
+            .map(|e| (*e.key(), *e.value()))
+            .collect::<Vec<_>>();
+        statuses.sort();
+        for (status, count) in statuses {
+            let labels = labels(&[("operation", e.key()), ("status", &status.to_string())]);
+            sample(out, name, &labels, count as f64);
+        }
+    }
+    let name = "graphql_request_failures_total";
+    header(
+        out,
+        name,
+        "counter",
+        "Upstream GraphQL requests that got no response.",
+    );
+    for e in &metrics {
+        let labels = labels(&[("operation", e.key())]);
+        sample(
+            out,
+            name,
+            &labels,
+            e.failures.load(Ordering::Relaxed) as f64,
+        );
+    }
+    let name = "graphql_errors_total";
+    header(
+        out,
+        name,
+        "counter",
+        "GraphQL error objects returned by upstream.",
+    );
+    for e in &metrics {
+        let labels = labels(&[("operation", e.key())]);
+        sample(out, name, &labels, e.errors.load(Ordering::Relaxed) as f64);
+    }
+}
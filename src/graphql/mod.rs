@@ -0,0 +1,529 @@
+use std::{
+    fmt::{Display, Formatter},
+    marker::PhantomData,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use reqwest::{Client, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::{spawn, sync::OnceCell};
+use tracing::{debug, debug_span, field, Instrument};
+
+use crate::states::config::Config;
+
+use self::cache::Lookup;
+
+pub mod cache;
+pub mod metrics;
+
+const ENDPOINT: &str = "https://page.kakao.com/graphql";
+
+pub struct Operation<V, S> {
+    pub document: Document,
+    marker: PhantomData<fn(V) -> S>,
+}
+
+#[derive(Serialize)]
+pub struct Document {
+    pub kind: Kind,
+    pub name: &'static str,
+    pub hash: &'static str,
+    pub query: &'static str,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Query,
+    Mutation,
+}
+
+impl<V, S> Operation<V, S> {
+    pub const fn new(document: Document) -> Self {
+        Self {
+            document,
+            marker: PhantomData,
+        }
+    }
+}
+
+inventory::collect!(Document);
+
+// reqwest clients have no identity of their own, so the account a client sends
+// requests as travels with it, the shared client belongs to none
+#[derive(Clone)]
+pub struct Upstream {
+    client: Client,
+    account: Option<i64>,
+}
+
+impl Upstream {
+    pub fn shared(client: Client) -> Self {
+        Self {
+            client,
+            account: None,
+        }
+    }
+
+    pub fn account(client: Client, account: i64) -> Self {
+        Self {
+            client,
+            account: Some(account),
+        }
+    }
+
+    // tells apart requests that must not share a response
+    fn key(&self) -> String {
+        match self.account {
+            Some(account) => format!("account {account}"),
+            None => "shared".to_string(),
+        }
+    }
+}
+
+impl Deref for Upstream {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+pub fn documents() -> Vec<&'static Document> {
+    let mut documents = inventory::iter::<Document>().collect::<Vec<_>>();
+    documents.sort_by_key(|e| e.name);
+    documents
+}
+
+static PERSISTED_QUERIES: AtomicBool = AtomicBool::new(false);
+
+pub fn configure(config: &Config) {
+    PERSISTED_QUERIES.store(config.persisted_queries, Ordering::Relaxed);
+    cache::init(&config.cache);
+}
+
+#[derive(Debug)]
+pub struct GraphQlError {
+    pub status: StatusCode,
+    pub errors: Vec<ErrorObject>,
+    pub data: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ErrorObject {
+    pub message: String,
+    #[serde(default)]
+    pub path: Vec<Value>,
+    #[serde(default)]
+    pub extensions: Map<String, Value>,
+}
+
+// extension codes upstream fails the ticket mutations with, kept in one place
+// since they are matched on rather than the translated messages
+pub const SESSION_EXPIRED: &str = "UNAUTHENTICATED";
+pub const NOT_ENOUGH_TICKETS: &str = "NOT_ENOUGH_TICKET";
+
+impl GraphQlError {
+    pub fn has_code(&self, code: &str) -> bool {
+        self.errors.iter().any(|e| e.code() == Some(code))
+    }
+
+    pub fn codes(&self) -> Vec<&str> {
+        self.errors.iter().filter_map(|e| e.code()).collect()
+    }
+}
+
+impl ErrorObject {
+    pub fn code(&self) -> Option<&str> {
+        self.extensions.get("code")?.as_str()
+    }
+
+    fn is_persisted_query_miss(&self) -> bool {
+        self.message == "PersistedQueryNotFound" || self.code() == Some("PERSISTED_QUERY_NOT_FOUND")
+    }
+}
+
+impl Display for GraphQlError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let messages = self
+            .errors
+            .iter()
+            .map(|e| e.message.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        f.write_str(&messages)
+    }
+}
+
+impl std::error::Error for GraphQlError {}
+
+#[derive(Serialize)]
+struct Request<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    query: Option<&'a str>,
+    variables: &'a Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extensions: Option<Extensions<'a>>,
+}
+
+#[derive(Serialize)]
+struct Extensions<'a> {
+    #[serde(rename = "persistedQuery")]
+    persisted_query: PersistedQuery<'a>,
+}
+
+#[derive(Serialize)]
+struct PersistedQuery<'a> {
+    version: i32,
+    #[serde(rename = "sha256Hash")]
+    sha256_hash: &'a str,
+}
+
+#[derive(Deserialize)]
+struct Success<S> {
+    data: S,
+}
+
+#[derive(Deserialize)]
+struct Failure {
+    #[serde(default)]
+    data: Option<Value>,
+    errors: Vec<ErrorObject>,
+}
+
+pub async fn execute<V: Serialize, S: DeserializeOwned>(
+    client: Upstream,
+    operation: &'static Operation<V, S>,
+    vars: V,
+) -> Result<S> {
+    let document = &operation.document;
+    let span = debug_span!(
+        "graphql",
+        operation = document.name,
+        account = client.account,
+        latency_ms = field::Empty,
+        status = field::Empty,
+        errors = field::Empty,
+    );
+    let start = Instant::now();
+    let res = fetch(client, document, serde_json::to_value(vars)?)
+        .instrument(span.clone())
+        .await;
+    span.record("latency_ms", start.elapsed().as_millis() as u64);
+    let (status, res) = match res {
+        Ok(res) => res,
+        Err(e) => {
+            debug!(parent: &span, "request failed: {e}");
+            return Err(e);
+        }
+    };
+    span.record("status", status.as_u16());
+    span.record("errors", error_count(&res));
+    debug!(parent: &span, "request completed");
+    decode(status, res)
+}
+
+fn error_count(res: &Value) -> usize {
+    res.get("errors")
+        .and_then(Value::as_array)
+        .map_or(0, Vec::len)
+}
+
+// only requests that reach upstream are measured, cache hits are counted by
+// the cache and coalesced requests only once
+fn observe(name: &'static str, latency: Duration, res: &Result<(StatusCode, Value)>) {
+    match res {
+        Ok((status, res)) => metrics::record(name, latency, Some(*status), error_count(res)),
+        Err(_) => metrics::record(name, latency, None, 0),
+    }
+}
+
+async fn fetch(
+    client: Upstream,
+    document: &'static Document,
+    variables: Value,
+) -> Result<(StatusCode, Value)> {
+    if document.kind == Kind::Mutation {
+        return send(&client, document, &variables).await;
+    }
+    if let Some(res) = cached(&client, document, &variables) {
+        return Ok(res);
+    }
+    let res = coalesce(&client, document, &variables).await?;
+    remember(&client, document, &variables, &res);
+    Ok(res)
+}
+
+// answers a query from its cache, a stale entry is still served while it is
+// refreshed in the background
+fn cached(
+    client: &Upstream,
+    document: &'static Document,
+    variables: &Value,
+) -> Option<(StatusCode, Value)> {
+    let cache = cache::get(document.name)?;
+    match cache.lookup(&cache_key(client, variables)) {
+        Lookup::Fresh(status, res) => Some((status, res)),
+        Lookup::Stale(status, res) => {
+            let (client, variables) = (client.clone(), variables.clone());
+            spawn(async move {
+                if let Ok(res) = coalesce(&client, document, &variables).await {
+                    remember(&client, document, &variables, &res);
+                }
+            });
+            Some((status, res))
+        }
+        Lookup::Miss => None,
+    }
+}
+
+fn remember(
+    client: &Upstream,
+    document: &'static Document,
+    variables: &Value,
+    (status, res): &(StatusCode, Value),
+) {
+    if let Some(cache) = cache::get(document.name) {
+        cache.store(cache_key(client, variables), *status, res);
+    }
+}
+
+// the same query can answer differently depending on the account
+fn cache_key(client: &Upstream, variables: &Value) -> String {
+    format!("{} {variables}", client.key())
+}
+
+async fn send(
+    client: &Client,
+    document: &'static Document,
+    variables: &Value,
+) -> Result<(StatusCode, Value)> {
+    let start = Instant::now();
+    let res = exchange(client, document, variables).await;
+    observe(document.name, start.elapsed(), &res);
+    res
+}
+
+// with persisted queries on, only the hash is sent at first and the full text
+// follows when upstream has not seen the hash yet
+async fn exchange(
+    client: &Client,
+    document: &Document,
+    variables: &Value,
+) -> Result<(StatusCode, Value)> {
+    if !PERSISTED_QUERIES.load(Ordering::Relaxed) {
+        let request = Request {
+            query: Some(document.query),
+            variables,
+            extensions: None,
+        };
+        return post(client, &request).await;
+    }
+    let extensions = || {
+        Some(Extensions {
+            persisted_query: PersistedQuery {
+                version: 1,
+                sha256_hash: document.hash,
+            },
+        })
+    };
+    let request = Request {
+        query: None,
+        variables,
+        extensions: extensions(),
+    };
+    let (status, res) = post(client, &request).await?;
+    let missed = if let Some(errors) = res.get("errors") {
+        serde_json::from_value::<Vec<ErrorObject>>(errors.clone())?
+            .iter()
+            .any(|e| e.is_persisted_query_miss())
+    } else {
+        false
+    };
+    if missed {
+        let request = Request {
+            query: Some(document.query),
+            variables,
+            extensions: extensions(),
+        };
+        post(client, &request).await
+    } else {
+        Ok((status, res))
+    }
+}
+
+async fn post(client: &Client, body: &impl Serialize) -> Result<(StatusCode, Value)> {
+    let res = client.post(ENDPOINT).json(body).send().await?;
+    let status = res.status();
+    Ok((status, res.json().await?))
+}
+
+fn decode<S: DeserializeOwned>(status: StatusCode, res: Value) -> Result<S> {
+    if res.get("errors").is_some() {
+        let Failure { data, errors } = serde_json::from_value(res)?;
+        Err(GraphQlError {
+            status,
+            errors,
+            data,
+        })?
+    } else {
+        Ok(serde_json::from_value::<Success<S>>(res)?.data)
+    }
+}
+
+type InFlight = Arc<OnceCell<std::result::Result<(StatusCode, Value), String>>>;
+
+static IN_FLIGHT: OnceLock<DashMap<String, InFlight>> = OnceLock::new();
+
+// identical queries sent through the same client while one is already in flight
+// share its response
+async fn coalesce(
+    client: &Upstream,
+    document: &'static Document,
+    variables: &Value,
+) -> Result<(StatusCode, Value)> {
+    let in_flight = IN_FLIGHT.get_or_init(DashMap::new);
+    let key = format!("{} {} {variables}", client.key(), document.name);
+    let cell = in_flight.entry(key.clone()).or_default().clone();
+    let res = cell
+        .get_or_init(|| async {
+            send(client, document, variables)
+                .await
+                .map_err(|e| e.to_string())
+        })
+        .await
+        .clone();
+    in_flight.remove_if(&key, |_, e| Arc::ptr_eq(e, &cell));
+    res.map_err(|e| anyhow!(e))
+}
+
+// upstream runs the operations of a batch in no particular order, so only ones
+// that do not depend on each other belong in the same batch
+pub struct Batch {
+    client: Upstream,
+    enabled: bool,
+    requests: Vec<(&'static Document, Value)>,
+}
+
+pub struct Slot<S> {
+    index: usize,
+    marker: PhantomData<fn() -> S>,
+}
+
+pub struct Responses(Vec<Option<(StatusCode, Value)>>);
+
+impl Batch {
+    // queries with a cache policy are answered from the cache first and only
+    // the misses go upstream, when disabled or with a single miss they are
+    // sent in order one by one, each coalesced like any other request, a
+    // batch sent as one request shares nothing with requests already in flight
+    pub fn new(client: Upstream, enabled: bool) -> Self {
+        Self {
+            client,
+            enabled,
+            requests: Vec::new(),
+        }
+    }
+
+    pub fn push<V: Serialize, S>(
+        &mut self,
+        operation: &'static Operation<V, S>,
+        vars: V,
+    ) -> Result<Slot<S>> {
+        self.requests
+            .push((&operation.document, serde_json::to_value(vars)?));
+        Ok(Slot {
+            index: self.requests.len() - 1,
+            marker: PhantomData,
+        })
+    }
+
+    pub async fn send(self) -> Result<Responses> {
+        let mut list = Vec::new();
+        let mut misses = Vec::new();
+        for (index, (document, variables)) in self.requests.iter().enumerate() {
+            let hit = match document.kind {
+                Kind::Mutation => None,
+                _ => cached(&self.client, document, variables),
+            };
+            if hit.is_none() {
+                misses.push(index);
+            }
+            list.push(hit);
+        }
+        if self.enabled && misses.len() > 1 {
+            let body = misses
+                .iter()
+                .map(|&index| &self.requests[index])
+                .map(|(document, variables)| Request {
+                    query: Some(document.query),
+                    variables,
+                    extensions: None,
+                })
+                .collect::<Vec<_>>();
+            // the status belongs to the whole batch, so a failed one fails
+            // every request in it rather than handing each the same status
+            let start = Instant::now();
+            let res = post(&self.client, &body).await;
+            let latency = start.elapsed();
+            let (status, res) = match res {
+                Ok(res) => res,
+                Err(e) => {
+                    for &index in &misses {
+                        let (document, _) = &self.requests[index];
+                        metrics::record(document.name, latency, None, 0);
+                    }
+                    return Err(e);
+                }
+            };
+            if !status.is_success() {
+                for &index in &misses {
+                    let (document, _) = &self.requests[index];
+                    metrics::record(document.name, latency, Some(status), 0);
+                }
+                Err(anyhow!("batch of {} failed with {status}", misses.len()))?
+            }
+            let res = serde_json::from_value::<Vec<Value>>(res)?;
+            if res.len() != misses.len() {
+                Err(anyhow!(
+                    "batch of {} returned {} responses",
+                    misses.len(),
+                    res.len()
+                ))?
+            }
+            for (index, res) in misses.into_iter().zip(res) {
+                let (document, variables) = &self.requests[index];
+                metrics::record(document.name, latency, Some(status), error_count(&res));
+                let res = (status, res);
+                if document.kind != Kind::Mutation {
+                    remember(&self.client, document, variables, &res);
+                }
+                list[index] = Some(res);
+            }
+        } else {
+            for index in misses {
+                let (document, variables) = &self.requests[index];
+                let res = fetch(self.client.clone(), document, variables.clone()).await?;
+                list[index] = Some(res);
+            }
+        }
+        Ok(Responses(list))
+    }
+}
+
+impl Responses {
+    pub fn take<S: DeserializeOwned>(&mut self, slot: Slot<S>) -> Result<S> {
+        let (status, res) = self.0[slot.index]
+            .take()
+            .ok_or_else(|| anyhow!("batch response {} was already taken", slot.index))?;
+        decode(status, res)
+    }
+}
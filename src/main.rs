@@ -1,50 +1,288 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
 use anyhow::Result;
 use axum::{
     body::Body,
-    http::Request,
+    extract::{MatchedPath, Query, State},
+    http::{header, HeaderValue, Method, Request, StatusCode},
     middleware::{self, Next},
-    response::Response,
-    routing::get,
-    Router, Server,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use endpoints::{
+    accounts::rewards,
+    cache::cache,
+    episodes::episodes,
+    jobs::{cancel_job, job_history, jobs, run_job},
+    keys::{create_key, key_tickets, keys, revoke_key},
+    library::{library, subscribe, unsubscribe},
+    metrics::metrics,
+    openapi::openapi,
+    operations::operations,
+    progress::{continue_reading, mark, progress},
+    resource::resource,
+    search::{search, suggest},
+    series::{series, series_meta},
+    single::single,
+    usage::usage,
 };
-use endpoints::{resource::resource, search::search, series::series, single::single};
-use env_logger::{Env, DEFAULT_FILTER_ENV};
-use log::info;
-use states::States;
+use log::{info, warn};
+use metrics::HTTP_REQUESTS;
+use states::{auth::Scope, States};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{spawn, sync::watch};
+use tracing_subscriber::EnvFilter;
 
 pub mod endpoints;
+pub mod graphql;
+pub mod metrics;
+pub mod scheduler;
+pub mod server;
 pub mod states;
+pub mod tls;
 pub mod util;
 
-async fn cors(request: Request<Body>, next: Next<Body>) -> Response {
-    let mut response = next.run(request).await;
+// preflight requests are answered here, before auth, since browsers never
+// send credentials with them
+async fn cors(
+    State(states): State<Arc<States>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let config = &states.config.cors;
+    let origin = request
+        .headers()
+        .get(header::ORIGIN)
+        .and_then(|e| e.to_str().ok())
+        .map(|e| e.to_string());
+    let preflight = request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    let mut response = if preflight {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        next.run(request).await
+    };
+    // the cors headers depend on the origin, so a cache must not hand a denied
+    // or origin-less response to an allowed origin
+    let headers = response.headers_mut();
+    headers.append(header::VARY, HeaderValue::from_static("origin"));
+    let Some(origin) = origin.filter(|e| config.allows(e)) else {
+        return response;
+    };
+    // credentials with the wildcard are rejected when the config is loaded
+    if config.allowed_origins.iter().any(|e| e == "*") {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            HeaderValue::from_static("*"),
+        );
+    } else if let Ok(origin) = HeaderValue::from_str(&origin) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    }
+    if config.allow_credentials {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+    let list = |values: &[String]| HeaderValue::from_str(&values.join(", ")).ok();
+    if preflight {
+        if let Some(methods) = list(&config.allowed_methods) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        if let Some(allowed) = list(&config.allowed_headers) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+        }
+        if let Some(max_age) = config.max_age {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, max_age.into());
+        }
+    } else if !config.exposed_headers.is_empty() {
+        if let Some(exposed) = list(&config.exposed_headers) {
+            headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed);
+        }
+    }
     response
-        .headers_mut()
-        .insert("access-control-allow-origin", "*".parse().unwrap());
+}
+
+// anything not listed here is an admin route
+fn route_scope(route: &str) -> Scope {
+    match route {
+        "/:resty/resource"
+        | "/episodes"
+        | "/library"
+        | "/library/subscribe"
+        | "/library/unsubscribe"
+        // scrapers get a browse key rather than an admin one
+        | "/metrics"
+        | "/openapi.json"
+        | "/progress"
+        | "/progress/continue"
+        | "/progress/mark"
+        | "/search"
+        | "/search/suggest"
+        | "/series"
+        | "/series/meta"
+        | "/usage" => Scope::Browse,
+        // spending tickets is checked by the handler itself
+        "/single" => Scope::Free,
+        _ => Scope::Admin,
+    }
+}
+
+// the routes that query upstream on behalf of the caller
+const RATE_LIMITED: [&str; 7] = [
+    "/episodes",
+    "/library/subscribe",
+    "/search",
+    "/search/suggest",
+    "/series",
+    "/series/meta",
+    "/single",
+];
+
+// the token can come as a bearer token, an x-api-key header or an api_key
+// query parameter for clients that can not set headers, like image tags
+fn request_token(request: &Request<Body>) -> Option<String> {
+    let headers = request.headers();
+    if let Some(value) = headers.get(header::AUTHORIZATION) {
+        let value = value.to_str().ok()?;
+        return value.strip_prefix("Bearer ").map(|e| e.trim().to_string());
+    }
+    if let Some(value) = headers.get("x-api-key") {
+        return value.to_str().ok().map(|e| e.to_string());
+    }
+    let Query(mut query) = Query::<HashMap<String, String>>::try_from_uri(request.uri()).ok()?;
+    query.remove("api_key")
+}
+
+async fn auth(
+    State(states): State<Arc<States>>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let caller = if states.auth_enabled() {
+        let Some(token) = request_token(&request) else {
+            let headers = [(header::WWW_AUTHENTICATE, "Bearer")];
+            return (StatusCode::UNAUTHORIZED, headers, "missing api key").into_response();
+        };
+        let Some(caller) = states.authenticate(&token) else {
+            let headers = [(header::WWW_AUTHENTICATE, "Bearer")];
+            return (StatusCode::UNAUTHORIZED, headers, "unknown api key").into_response();
+        };
+        caller
+    } else {
+        states.anonymous()
+    };
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|e| e.as_str().to_string())
+        .unwrap_or_default();
+    if caller.scope < route_scope(&route) {
+        let message = format!("api key {} may not access {route}", caller.name);
+        return (StatusCode::FORBIDDEN, message).into_response();
+    }
+    if RATE_LIMITED.contains(&route.as_str()) {
+        if let Err(e) = states.hit_rate_limit(&caller) {
+            return e.into_response();
+        }
+    }
+    request.extensions_mut().insert(caller);
+    next.run(request).await
+}
+
+async fn track(request: Request<Body>, next: Next<Body>) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|e| e.as_str().to_string())
+        .unwrap_or_default();
+    let response = next.run(request).await;
+    let status = response.status().as_u16();
+    HTTP_REQUESTS.inc(&[("route", &route), ("status", &status.to_string())]);
     response
 }
 
 async fn tsig() {
-    tokio::signal::ctrl_c().await.unwrap();
+    #[cfg(unix)]
+    let terminate = async {
+        signal(SignalKind::terminate()).unwrap().recv().await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
     info!("stopping")
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init_from_env(Env::new().filter_or(DEFAULT_FILTER_ENV, "info"));
+    // log records from this crate and its dependencies go through the same
+    // subscriber as the spans
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
     let states = States::load()?;
+    graphql::configure(&states.config);
+    if !states.auth_enabled() {
+        warn!("auth is off, every endpoint is open");
+    }
     let router = Router::new()
         .route("/:resty/resource", get(resource))
+        .route("/accounts/rewards", get(rewards))
+        .route("/cache", get(cache))
+        .route("/episodes", get(episodes))
+        .route("/jobs", get(jobs))
+        .route("/jobs/cancel", post(cancel_job))
+        .route("/jobs/history", get(job_history))
+        .route("/jobs/run", post(run_job))
+        .route("/keys", get(keys))
+        .route("/keys/create", post(create_key))
+        .route("/keys/revoke", post(revoke_key))
+        .route("/keys/tickets", get(key_tickets))
+        .route("/library", get(library))
+        .route("/library/subscribe", post(subscribe))
+        .route("/library/unsubscribe", post(unsubscribe))
+        .route("/metrics", get(metrics))
+        .route("/openapi.json", get(openapi))
+        .route("/operations", get(operations))
+        .route("/progress", get(progress))
+        .route("/progress/continue", get(continue_reading))
+        .route("/progress/mark", post(mark))
         .route("/search", get(search))
+        .route("/search/suggest", get(suggest))
         .route("/series", get(series))
+        .route("/series/meta", get(series_meta))
         .route("/single", get(single))
-        .layer(middleware::from_fn(cors))
+        .route("/usage", get(usage))
+        .route_layer(middleware::from_fn_with_state(states.clone(), auth))
+        .route_layer(middleware::from_fn(track))
+        .layer(middleware::from_fn_with_state(states.clone(), cors))
         .with_state::<()>(states.clone());
-    states.start_timers();
-    Server::bind(&states.config.bind_addr)
-        .serve(router.into_make_service())
-        .with_graceful_shutdown(tsig())
-        .await?;
+    let timers = states.start_timers();
+    let (stop, stopped) = watch::channel(false);
+    spawn(async move {
+        tsig().await;
+        let _ = stop.send(true);
+    });
+    // a listener failing at runtime still lets the jobs finish and the state
+    // be saved
+    let served = server::serve(&states.config, router, stopped).await;
+    for timer in timers {
+        timer.abort();
+    }
+    let deadline = Duration::from_secs(states.config.shutdown_timeout);
+    info!("waiting for running jobs");
+    if !states.scheduler.shutdown(deadline).await {
+        warn!("jobs still running after {deadline:?}, saving anyway");
+    }
     states.save()?;
-    Ok(())
+    served
 }
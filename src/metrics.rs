@@ -0,0 +1,214 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use dashmap::DashMap;
+
+use crate::{
+    graphql::{self, cache},
+    scheduler::Priority,
+    states::States,
+    util::now,
+};
+
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            let count = bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {count}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {sum}");
+        let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+    }
+}
+
+pub fn header(out: &mut String, name: &str, typ_: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {typ_}");
+}
+
+// values can come from upstream, like ticket types, so they are escaped as the
+// text format asks
+pub fn labels(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn sample(out: &mut String, name: &str, labels: &str, value: f64) {
+    if labels.is_empty() {
+        let _ = writeln!(out, "{name} {value}");
+    } else {
+        let _ = writeln!(out, "{name}{{{labels}}} {value}");
+    }
+}
+
+pub struct CounterVec {
+    name: &'static str,
+    help: &'static str,
+    values: OnceLock<DashMap<String, u64>>,
+}
+
+impl CounterVec {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            values: OnceLock::new(),
+        }
+    }
+
+    pub fn inc(&self, pairs: &[(&str, &str)]) {
+        self.inc_by(pairs, 1)
+    }
+
+    pub fn inc_by(&self, pairs: &[(&str, &str)], by: u64) {
+        let values = self.values.get_or_init(DashMap::new);
+        *values.entry(labels(pairs)).or_default() += by;
+    }
+
+    pub fn render(&self, out: &mut String) {
+        header(out, self.name, "counter", self.help);
+        let mut values = self
+            .values
+            .get()
+            .into_iter()
+            .flatten()
+            .map(|e| (e.key().clone(), *e.value()))
+            .collect::<Vec<_>>();
+        values.sort();
+        for (labels, value) in values {
+            sample(out, self.name, &labels, value as f64);
+        }
+    }
+}
+
+pub static HTTP_REQUESTS: CounterVec = CounterVec::new(
+    "http_requests_total",
+    "Requests served by route and status.",
+);
+
+pub static RESOURCE_BYTES: CounterVec = CounterVec::new(
+    "resource_proxy_bytes_total",
+    "Bytes proxied by the resource endpoint.",
+);
+
+pub static TICKETS_SPENT: CounterVec = CounterVec::new(
+    "tickets_spent_total",
+    "Tickets used upstream by ticket type and api key.",
+);
+
+pub static JOB_RUNS: CounterVec = CounterVec::new(
+    "timer_job_runs_total",
+    "Background job runs by job and outcome.",
+);
+
+pub fn render(states: &States) -> String {
+    let mut out = String::new();
+    HTTP_REQUESTS.render(&mut out);
+    RESOURCE_BYTES.render(&mut out);
+    TICKETS_SPENT.render(&mut out);
+    JOB_RUNS.render(&mut out);
+    let jobs = states.scheduler.jobs();
+    let name = "scheduler_jobs_running";
+    header(&mut out, name, "gauge", "Scheduler jobs currently running.");
+    let running = jobs.iter().filter(|e| e.started_at.is_some()).count();
+    sample(&mut out, name, "", running as f64);
+    let name = "scheduler_jobs_waiting";
+    header(
+        &mut out,
+        name,
+        "gauge",
+        "Scheduler jobs waiting for a slot by priority.",
+    );
+    for priority in [Priority::User, Priority::Background] {
+        let waiting = jobs
+            .iter()
+            .filter(|e| e.started_at.is_none() && e.priority == priority)
+            .count();
+        let labels = labels(&[("priority", priority.as_str())]);
+        sample(&mut out, name, &labels, waiting as f64);
+    }
+    let name = "account_balance";
+    header(&mut out, name, "gauge", "Cash balance of each account.");
+    let mut balances = states
+        .accounts
+        .iter()
+        .map(|e| (*e.key(), e.balance))
+        .collect::<Vec<_>>();
+    balances.sort();
+    for (account, balance) in balances {
+        sample(
+            &mut out,
+            name,
+            &labels(&[("account", &account.to_string())]),
+            balance as f64,
+        );
+    }
+    let name = "wait_free_tickets_available";
+    header(
+        &mut out,
+        name,
+        "gauge",
+        "Accounts with a charged wait-free ticket per series.",
+    );
+    let mut serieses = states
+        .serieses
+        .iter()
+        .map(|e| {
+            let available = e.ticket_map.iter().filter(|e| now() > e.wait_free).count();
+            (*e.key(), available)
+        })
+        .collect::<Vec<_>>();
+    serieses.sort();
+    for (series, available) in serieses {
+        sample(
+            &mut out,
+            name,
+            &labels(&[("series", &series.to_string())]),
+            available as f64,
+        );
+    }
+    cache::render(&mut out);
+    graphql::metrics::render(&mut out);
+    out
+}
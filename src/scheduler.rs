@@ -0,0 +1,264 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::{
+    spawn,
+    sync::{
+        oneshot::{channel, Sender},
+        Notify,
+    },
+    task::{AbortHandle, JoinHandle},
+    time::timeout,
+};
+
+use crate::util::now;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Background,
+    User,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Background => "background",
+            Priority::User => "user",
+        }
+    }
+}
+
+pub struct Job {
+    name: String,
+    priority: Priority,
+    account: Option<i64>,
+}
+
+impl Job {
+    pub fn new(name: impl ToString) -> Self {
+        Self {
+            name: name.to_string(),
+            priority: Priority::Background,
+            account: None,
+        }
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn account(mut self, account: i64) -> Self {
+        self.account = Some(account);
+        self
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct JobInfo {
+    pub id: u64,
+    pub name: String,
+    pub priority: Priority,
+    pub account: Option<i64>,
+    pub queued_at: i64,
+    pub started_at: Option<i64>,
+}
+
+struct Entry {
+    info: JobInfo,
+    start: Option<Sender<()>>,
+    abort: AbortHandle,
+}
+
+// waiting jobs are queued by priority and account, so finding the next one
+// only looks at the front of each queue
+#[derive(Default)]
+struct Jobs {
+    entries: BTreeMap<u64, Entry>,
+    waiting: BTreeMap<(Priority, Option<i64>), VecDeque<u64>>,
+    running: usize,
+    account_running: HashMap<i64, usize>,
+}
+
+pub struct Scheduler {
+    limit: usize,
+    account_limit: usize,
+    next_id: AtomicU64,
+    jobs: Mutex<Jobs>,
+    closed: AtomicBool,
+    idle: Notify,
+}
+
+// releases the slot of a job however it ends, including when it is aborted
+struct Guard {
+    scheduler: Arc<Scheduler>,
+    id: u64,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let mut jobs = self.scheduler.jobs.lock().unwrap();
+        if let Some(entry) = jobs.entries.remove(&self.id) {
+            if entry.info.started_at.is_some() {
+                jobs.running -= 1;
+                if let Some(account) = entry.info.account {
+                    *jobs.account_running.entry(account).or_default() -= 1;
+                }
+            }
+        }
+        self.scheduler.dispatch(&mut jobs);
+        if jobs.entries.is_empty() {
+            self.scheduler.idle.notify_waiters();
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new(limit: usize, account_limit: usize) -> Self {
+        Self {
+            limit: limit.max(1),
+            account_limit: account_limit.max(1),
+            next_id: AtomicU64::new(0),
+            jobs: Mutex::default(),
+            closed: AtomicBool::new(false),
+            idle: Notify::new(),
+        }
+    }
+
+    pub fn spawn<T: Send + 'static>(
+        self: &Arc<Self>,
+        job: Job,
+        future: impl Future<Output = T> + Send + 'static,
+    ) -> JoinHandle<T> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (start_sender, start_recver) = channel();
+        let guard = Guard {
+            scheduler: self.clone(),
+            id,
+        };
+        let handle = spawn(async move {
+            let _guard = guard;
+            let _ = start_recver.await;
+            future.await
+        });
+        let entry = Entry {
+            info: JobInfo {
+                id,
+                name: job.name,
+                priority: job.priority,
+                account: job.account,
+                queued_at: now(),
+                started_at: None,
+            },
+            start: Some(start_sender),
+            abort: handle.abort_handle(),
+        };
+        let mut jobs = self.jobs.lock().unwrap();
+        if self.closed.load(Ordering::Relaxed) {
+            entry.abort.abort();
+        }
+        let queue = (entry.info.priority, entry.info.account);
+        jobs.waiting.entry(queue).or_default().push_back(id);
+        jobs.entries.insert(id, entry);
+        self.dispatch(&mut jobs);
+        handle
+    }
+
+    // stops starting jobs, drops the ones still waiting and gives the running
+    // ones until the deadline to finish, returns whether they all did
+    pub async fn shutdown(&self, deadline: Duration) -> bool {
+        {
+            let jobs = self.jobs.lock().unwrap();
+            self.closed.store(true, Ordering::Relaxed);
+            for entry in jobs.entries.values() {
+                if entry.info.started_at.is_none() {
+                    entry.abort.abort();
+                }
+            }
+        }
+        let drained = async {
+            loop {
+                let idle = self.idle.notified();
+                if self.jobs.lock().unwrap().entries.is_empty() {
+                    break;
+                }
+                idle.await;
+            }
+        };
+        timeout(deadline, drained).await.is_ok()
+    }
+
+    pub fn cancel(&self, id: u64) -> bool {
+        let jobs = self.jobs.lock().unwrap();
+        if let Some(entry) = jobs.entries.get(&id) {
+            entry.abort.abort();
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn jobs(&self) -> Vec<JobInfo> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.entries.values().map(|e| e.info.clone()).collect()
+    }
+
+    // starts the most urgent waiting jobs, oldest first, as long as the global
+    // and per account limits allow
+    fn dispatch(&self, jobs: &mut Jobs) {
+        if self.closed.load(Ordering::Relaxed) {
+            return;
+        }
+        let Jobs {
+            entries,
+            waiting,
+            running,
+            account_running,
+        } = jobs;
+        while *running < self.limit {
+            let mut next: Option<((Priority, Option<i64>), u64)> = None;
+            for (queue, ids) in waiting.iter_mut() {
+                // jobs aborted while waiting are already gone from entries
+                while ids.front().is_some_and(|e| !entries.contains_key(e)) {
+                    ids.pop_front();
+                }
+                let Some(&id) = ids.front() else {
+                    continue;
+                };
+                let full = queue.1.is_some_and(|account| {
+                    account_running.get(&account).copied().unwrap_or(0) >= self.account_limit
+                });
+                let better = next.is_none_or(|(best, best_id)| {
+                    queue.0 > best.0 || (queue.0 == best.0 && id < best_id)
+                });
+                if !full && better {
+                    next = Some((*queue, id));
+                }
+            }
+            let Some((queue, id)) = next else {
+                break;
+            };
+            waiting.get_mut(&queue).unwrap().pop_front();
+            *running += 1;
+            if let Some(account) = queue.1 {
+                *account_running.entry(account).or_default() += 1;
+            }
+            let entry = entries.get_mut(&id).unwrap();
+            entry.info.started_at = Some(now());
+            if let Some(start) = entry.start.take() {
+                let _ = start.send(());
+            }
+        }
+        waiting.retain(|_, e| !e.is_empty());
+        account_running.retain(|_, e| *e > 0);
+    }
+}
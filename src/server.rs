@@ -0,0 +1,237 @@
+use std::{
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Context, Result};
+use axum::{Router, Server};
+use axum_server::Handle;
+use log::{info, warn};
+use tokio::{spawn, sync::watch, task::JoinSet};
+
+use crate::{
+    states::config::{Config, Listener, Tls},
+    tls,
+};
+
+async fn stopped(mut stop: watch::Receiver<bool>) {
+    let _ = stop.wait_for(|e| *e).await;
+}
+
+fn bind_tcp(addr: &SocketAddr) -> Result<TcpListener> {
+    let listener = TcpListener::bind(addr).with_context(|| format!("failed to bind {addr}"))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+enum Bound {
+    Tcp(SocketAddr, TcpListener),
+    Unix(PathBuf, unix::UnixAccept),
+}
+
+// every listener is bound before any is served, so a bad address stops startup
+// instead of leaving the others running on their own, and once serving, one
+// listener failing stops the others too
+pub async fn serve(config: &Config, router: Router, stop: watch::Receiver<bool>) -> Result<()> {
+    let rustls = match &config.tls {
+        Some(tls) => Some(tls::load(tls).await?),
+        None => None,
+    };
+    let mut bound = Vec::new();
+    for listener in config.listeners() {
+        bound.push(match listener {
+            Listener::Tcp { addr } => Bound::Tcp(addr, bind_tcp(&addr)?),
+            Listener::Unix { path, mode } => {
+                let listener = unix::bind(&path, &mode)?;
+                Bound::Unix(path, listener)
+            }
+        });
+    }
+    let redirect = match &config.tls {
+        Some(Tls {
+            redirect_addr: Some(redirect_addr),
+            ..
+        }) => {
+            let port = config
+                .listeners()
+                .iter()
+                .find_map(|e| match e {
+                    Listener::Tcp { addr } => Some(addr.port()),
+                    Listener::Unix { .. } => None,
+                })
+                .ok_or_else(|| anyhow!("redirect_addr needs a tcp listener to redirect to"))?;
+            Some((*redirect_addr, bind_tcp(redirect_addr)?, port))
+        }
+        _ => None,
+    };
+    let (stop_all, stopping) = watch::channel(false);
+    let forward = spawn({
+        let stop_all = stop_all.clone();
+        async move {
+            stopped(stop).await;
+            let _ = stop_all.send(true);
+        }
+    });
+    let mut servers = JoinSet::new();
+    for listener in bound {
+        let app = router.clone().into_make_service();
+        let stopping = stopped(stopping.clone());
+        match (listener, &rustls) {
+            (Bound::Tcp(addr, tcp), Some(rustls)) => {
+                info!("listening on https://{addr}");
+                let handle = Handle::new();
+                let graceful = handle.clone();
+                spawn(async move {
+                    stopping.await;
+                    graceful.graceful_shutdown(None);
+                });
+                let server = axum_server::from_tcp_rustls(tcp, rustls.clone())
+                    .handle(handle)
+                    .serve(app);
+                servers.spawn(async move {
+                    server
+                        .await
+                        .with_context(|| format!("listener {addr} failed"))
+                });
+            }
+            (Bound::Tcp(addr, tcp), None) => {
+                let server = Server::from_tcp(tcp)?
+                    .serve(app)
+                    .with_graceful_shutdown(stopping);
+                info!("listening on http://{addr}");
+                servers.spawn(async move {
+                    server
+                        .await
+                        .with_context(|| format!("listener {addr} failed"))
+                });
+            }
+            (Bound::Unix(path, listener), _) => {
+                info!("listening on {}", path.display());
+                let server = Server::builder(listener)
+                    .serve(app)
+                    .with_graceful_shutdown(stopping);
+                servers.spawn(async move {
+                    let result = server
+                        .await
+                        .with_context(|| format!("listener {} failed", path.display()));
+                    let _ = std::fs::remove_file(path);
+                    result
+                });
+            }
+        }
+    }
+    if let Some((redirect_addr, tcp, port)) = redirect {
+        let server = Server::from_tcp(tcp)?
+            .serve(tls::redirect(port).into_make_service())
+            .with_graceful_shutdown(stopped(stopping.clone()));
+        info!("redirecting http://{redirect_addr} to https");
+        servers.spawn(async move {
+            server
+                .await
+                .with_context(|| format!("redirect listener {redirect_addr} failed"))
+        });
+    }
+    let watcher = match (&config.tls, &rustls) {
+        (Some(tls), Some(rustls)) => Some(tls::watch(rustls.clone(), tls.clone())),
+        _ => None,
+    };
+    let mut result = Ok(());
+    while let Some(joined) = servers.join_next().await {
+        if let Err(e) = joined.map_err(anyhow::Error::from).and_then(|e| e) {
+            warn!("{e:#}, stopping the other listeners");
+            let _ = stop_all.send(true);
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    forward.abort();
+    if let Some(watcher) = watcher {
+        watcher.abort();
+    }
+    result
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        fs::{self, DirBuilder, Permissions},
+        os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        path::Path,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use anyhow::{anyhow, Result};
+    use hyper::server::accept::Accept;
+    use tokio::net::{UnixListener, UnixStream};
+
+    pub struct UnixAccept(UnixListener);
+
+    impl Accept for UnixAccept {
+        type Conn = UnixStream;
+        type Error = std::io::Error;
+
+        fn poll_accept(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+            self.0
+                .poll_accept(cx)
+                .map(|e| Some(e.map(|(stream, _)| stream)))
+        }
+    }
+
+    // a socket left behind by an unclean exit would make the bind fail, so one
+    // nobody answers on is removed, the socket is created in a directory only we
+    // can enter and moved into place once its mode is set
+    pub fn bind(path: &Path, mode: &str) -> Result<UnixAccept> {
+        let mode = u32::from_str_radix(mode, 8)
+            .map_err(|_| anyhow!("socket mode \"{mode}\" is not an octal number"))?;
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                Err(anyhow!("{} exists and is not a socket", path.display()))?
+            }
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                Err(anyhow!("{} is in use by another process", path.display()))?
+            }
+            fs::remove_file(path)?;
+        }
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("{} is not a socket path", path.display()))?;
+        let dir = path.with_file_name(format!(
+            ".{}.{}",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        DirBuilder::new().mode(0o700).create(&dir)?;
+        let staged = dir.join("socket");
+        let bound = UnixListener::bind(&staged)
+            .map_err(anyhow::Error::from)
+            .and_then(|listener| {
+                fs::set_permissions(&staged, Permissions::from_mode(mode))?;
+                fs::rename(&staged, path)?;
+                Ok(listener)
+            });
+        let _ = fs::remove_dir_all(&dir);
+        Ok(UnixAccept(bound?))
+    }
+}
+
+#[cfg(not(unix))]
+mod unix {
+    use std::path::Path;
+
+    use anyhow::{anyhow, Result};
+
+    pub type UnixAccept = hyper::server::accept::AddrIncoming;
+
+    pub fn bind(path: &Path, _: &str) -> Result<UnixAccept> {
+        Err(anyhow!(
+            "can not listen on {}, unix sockets are not supported on this platform",
+            path.display()
+        ))
+    }
+}
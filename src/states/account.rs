@@ -6,20 +6,28 @@ use std::{
 use anyhow::Result;
 use axum::http::HeaderMap;
 use cookie::Cookie;
+use log::{info, warn};
 use rand::random;
 use reqwest::{cookie::CookieStore, header::HeaderValue, Client, Proxy, Url};
 use serde::{Deserialize, Serialize};
 use tokio::time::sleep;
 use vitis_be_macros::macroql;
 
-use crate::util::{get_param, iso};
+use crate::{
+    metrics::TICKETS_SPENT,
+    util::{get_param, iso, now},
+};
 
 use self::{
     draw_gotcha::vars::DrawGotchaInput, gotchas::vars::MyNewsListInput,
     recv_ticket::vars::TicketFreeMutationInput,
 };
 
-use super::States;
+use super::{
+    auth::{Reservation, TicketUse},
+    series::Ticket,
+    States,
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct Account {
@@ -29,6 +37,8 @@ pub struct Account {
     pub last_gotcha_opened: i64,
     #[serde(default)]
     pub balance: i64,
+    #[serde(default)]
+    pub rewards: Vec<Reward>,
     token: Arc<Token>,
     #[serde(default = "generate_agent")]
     agent: String,
@@ -37,6 +47,30 @@ pub struct Account {
     client: OnceLock<Client>,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Reward {
+    pub at: i64,
+    #[serde(flatten)]
+    pub kind: RewardKind,
+    pub balance_change: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RewardKind {
+    Gotcha {
+        gotcha_id: String,
+        status: String,
+    },
+    Gift {
+        series_id: Option<i64>,
+        ticket_count: i64,
+    },
+}
+
+// older rewards are dropped so accounts.json does not grow forever
+const MAX_REWARDS: usize = 1000;
+
 #[derive(Serialize, Deserialize)]
 struct Token(Mutex<String>);
 
@@ -64,6 +98,55 @@ impl CookieStore for Token {
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountJob {
+    RefreshToken,
+    CheckGotchas,
+    CheckBalance,
+    CheckTickets,
+}
+
+impl AccountJob {
+    pub const ALL: [Self; 4] = [
+        Self::RefreshToken,
+        Self::CheckGotchas,
+        Self::CheckBalance,
+        Self::CheckTickets,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::RefreshToken => "refresh_token",
+            Self::CheckGotchas => "check_gotchas",
+            Self::CheckBalance => "check_balance",
+            Self::CheckTickets => "check_tickets",
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Self::RefreshToken => "refresh token",
+            Self::CheckGotchas => "check gotchas",
+            Self::CheckBalance => "check balance",
+            Self::CheckTickets => "check tickets",
+        }
+    }
+
+    pub async fn run(self, states: &States, key: i64) -> Result<()> {
+        match self {
+            Self::RefreshToken => {
+                let result = Account::refresh_token(states, key).await;
+                states.get_acc(key)?.last_token_refresh = now();
+                result
+            }
+            Self::CheckGotchas => Account::check_gotchas(states, key).await,
+            Self::CheckBalance => Account::check_balance(states, key).await,
+            Self::CheckTickets => Account::check_tickets(states, key).await,
+        }
+    }
+}
+
 pub fn generate_agent() -> String {
     format!("kakaopage/{:016x}", random::<u64>())
 }
@@ -133,6 +216,33 @@ macroql! {
     }
 }
 
+macroql! {
+    query ticket_check (
+        seriesId: Long,
+    ) {
+        contentCheckFreeTicket(seriesId) {
+            list: [] {
+                count: Long
+            }
+        }
+    }
+}
+
+macroql! {
+    query my_tickets (
+        seriesId: Long,
+        includeWaitfree: Boolean
+    ) {
+        contentMyTicket(seriesId, includeWaitfree) {
+            ticketOwnCount: Long,
+            ticketRentalCount: Long,
+            waitfree: ? {
+                chargedAt: String
+            }
+        }
+    }
+}
+
 impl Account {
     pub fn client(&self) -> Client {
         self.client
@@ -152,24 +262,79 @@ impl Account {
     }
 
     pub async fn refresh_token(states: &States, key: i64) -> Result<()> {
-        states
-            .get_acc(key)?
-            .client()
-            .head("https://page.kakao.com")
-            .send()
-            .await?;
+        let client = states.acc_client(key)?;
+        client.head("https://page.kakao.com").send().await?;
+        Ok(())
+    }
+
+    pub fn add_reward(&mut self, kind: RewardKind, balance_change: i64) {
+        self.rewards.push(Reward {
+            at: now(),
+            kind,
+            balance_change,
+        });
+        if self.rewards.len() > MAX_REWARDS {
+            let excess = self.rewards.len() - MAX_REWARDS;
+            self.rewards.drain(..excess);
+        }
+    }
+
+    pub async fn use_ticket(
+        states: &States,
+        reservation: &mut Reservation,
+        key: i64,
+        series_id: i64,
+        single_id: i64,
+        ticket_type: impl ToString,
+    ) -> Result<()> {
+        let ticket_type = ticket_type.to_string();
+        macroql! {
+            mutation use_ticket (
+                input: TicketUseMutationInput {
+                    productId: Long,
+                    ticketType: String
+                }
+            ) {
+                useTicket(input) {
+                    waitfreeChargedAt: String?
+                }
+            }
+        }
+        let sels = use_ticket(
+            states.acc_client(key)?,
+            use_ticket::Vars {
+                input: use_ticket::vars::TicketUseMutationInput {
+                    product_id: single_id,
+                    ticket_type: ticket_type.clone(),
+                },
+            },
+        )
+        .await?;
+        let name = &reservation.caller().name;
+        TICKETS_SPENT.inc(&[("type", &ticket_type), ("key", name)]);
+        info!("used {ticket_type} ticket of account {key} on {series_id}/{single_id} for {name}");
+        reservation.spend(TicketUse {
+            at: now(),
+            account: key,
+            series_id,
+            single_id,
+            ticket_type,
+        });
+        if let Some(wait_free) = sels.use_ticket.waitfree_charged_at {
+            states.get_srs(series_id)?.get_tkt(key)?.wait_free = iso(&wait_free)?;
+        }
         Ok(())
     }
 
     pub async fn check_balance(states: &States, key: i64) -> Result<()> {
-        let sels = balance(states.get_acc(key)?.client(), balance::Vars {}).await?;
+        let sels = balance(states.acc_client(key)?, balance::Vars {}).await?;
         states.get_acc(key)?.balance = sels.user_and_cash.cash.remain_cash;
         Ok(())
     }
 
     pub async fn check_gotchas(states: &States, key: i64) -> Result<()> {
         let sels = gotchas(
-            states.get_acc(key)?.client(),
+            states.acc_client(key)?,
             gotchas::Vars {
                 my_news_list_input: MyNewsListInput {
                     tab: "ALL".to_string(),
@@ -178,43 +343,71 @@ impl Account {
             },
         )
         .await?;
-        let mut max_date = 0;
+        let opened = states.get_acc(key)?.last_gotcha_opened;
+        let mut awards = Vec::new();
         for news in sels.my_news_list.news {
             if news.log_name == "Award" {
                 let date = iso(&news.date)?;
-                if date > states.get_acc(key)?.last_gotcha_opened {
-                    let gotcha_id = if let Ok(scheme) = get_param(
-                        &urlencoding::decode(&news.scheme)?,
-                        "open_enc_url_with_auth",
-                    ) {
-                        get_param(&urlencoding::decode(&scheme)?, "id")?
-                    } else {
-                        get_param(&urlencoding::decode(&news.scheme)?, "gacha_uid")?
-                    };
-                    draw_gotcha(
-                        states.get_acc(key)?.client(),
-                        draw_gotcha::Vars {
-                            input: DrawGotchaInput { gotcha_id },
-                        },
-                    )
-                    .await?;
-                    max_date = max_date.max(date);
-                    sleep(Duration::from_millis(200 + random::<u64>() % 200)).await;
+                if date > opened {
+                    awards.push((date, news.scheme));
                 }
             }
         }
-        if max_date > 0 {
-            states.get_acc(key)?.last_gotcha_opened = max_date;
+        // oldest first, so a failed draw leaves only newer ones for next time
+        awards.sort_by_key(|(date, _)| *date);
+        let mut balance_known = false;
+        for (date, scheme) in awards {
+            let gotcha_id = if let Ok(scheme) =
+                get_param(&urlencoding::decode(&scheme)?, "open_enc_url_with_auth")
+            {
+                get_param(&urlencoding::decode(&scheme)?, "id")?
+            } else {
+                get_param(&urlencoding::decode(&scheme)?, "gacha_uid")?
+            };
+            // the stored balance may be hours old, so the first change would
+            // include everything since the last check
+            if !balance_known {
+                match Account::check_balance(states, key).await {
+                    Ok(()) => balance_known = true,
+                    Err(e) => warn!("failed to check balance for account {key}: {e}"),
+                }
+            }
+            let drawn = draw_gotcha(
+                states.acc_client(key)?,
+                draw_gotcha::Vars {
+                    input: DrawGotchaInput {
+                        gotcha_id: gotcha_id.clone(),
+                    },
+                },
+            )
+            .await?;
+            let before = states.get_acc(key)?.balance;
+            // a change is only recorded when both balances are fresh
+            let balance_change = if !balance_known {
+                0
+            } else if let Err(e) = Account::check_balance(states, key).await {
+                warn!("failed to check balance for account {key}: {e}");
+                balance_known = false;
+                0
+            } else {
+                states.get_acc(key)?.balance - before
+            };
+            let mut account = states.get_acc(key)?;
+            let status = drawn.draw_gotcha.status;
+            account.add_reward(RewardKind::Gotcha { gotcha_id, status }, balance_change);
+            account.last_gotcha_opened = account.last_gotcha_opened.max(date);
+            drop(account);
+            sleep(Duration::from_millis(200 + random::<u64>() % 200)).await;
         }
         Ok(())
     }
 
     pub async fn check_tickets(states: &States, key: i64) -> Result<()> {
-        let sels = tickets(states.get_acc(key)?.client(), tickets::Vars {}).await?;
+        let sels = tickets(states.acc_client(key)?, tickets::Vars {}).await?;
         for gift in sels.today_gift_list.list {
             if !gift.is_received {
-                let _ = recv_ticket(
-                    states.get_acc(key)?.client(),
+                let received = recv_ticket(
+                    states.acc_client(key)?,
                     recv_ticket::Vars {
                         input: TicketFreeMutationInput {
                             typ_: "TodayGift".to_string(),
@@ -223,15 +416,76 @@ impl Account {
                     },
                 )
                 .await?;
-                // disabled as it would complicate the check first time free ticket logic
-                // states
-                //     .get_srs(get_param(&gift.scheme, "series_id")?.parse()?)?
-                //     .get_tkt(key)?
-                //     .permanent
-                //     .add_assign(sels.receive_ticket_free.ticket_count);
+                let ticket_count = received.receive_ticket_free.ticket_count;
+                let series_id = match get_param(&urlencoding::decode(&gift.scheme)?, "series_id") {
+                    Ok(series_id) => Some(series_id.parse()?),
+                    Err(e) => {
+                        warn!("could not attribute gift for account {key}: {e}");
+                        None
+                    }
+                };
+                let gift = RewardKind::Gift {
+                    series_id,
+                    ticket_count,
+                };
+                states.get_acc(key)?.add_reward(gift, 0);
+                if let Some(series_id) = series_id {
+                    Account::credit_gift(states, key, series_id, ticket_count).await?;
+                }
                 sleep(Duration::from_millis(200 + random::<u64>() % 200)).await;
             }
         }
         Ok(())
     }
+
+    // the upstream ticket counts are the source of truth, the gift count is only
+    // used when they cannot be fetched and the account is already known to have
+    // tickets for the series
+    async fn credit_gift(states: &States, key: i64, series_id: i64, count: i64) -> Result<()> {
+        let check_free = !states.get_srs(series_id)?.ticket_map.contains_key(&key);
+        if let Err(e) = Account::sync_tickets(states, key, series_id, check_free).await {
+            warn!("failed to sync tickets of series {series_id} for account {key}: {e}");
+            if let Ok(mut ticket) = states.get_srs(series_id)?.get_tkt(key) {
+                ticket.permanent += count;
+            }
+        }
+        Ok(())
+    }
+
+    // refreshes the tickets the account holds for a series, claiming the first
+    // time free tickets beforehand if asked to, and returns the permanent count
+    pub async fn sync_tickets(
+        states: &States,
+        key: i64,
+        series_id: i64,
+        check_free: bool,
+    ) -> Result<i64> {
+        // checking claims the free tickets, so the counts are only asked for
+        // once it is done, a batch would not guarantee that order
+        if check_free {
+            ticket_check(states.acc_client(key)?, ticket_check::Vars { series_id }).await?;
+        }
+        let sels = my_tickets(
+            states.acc_client(key)?,
+            my_tickets::Vars {
+                series_id,
+                include_waitfree: true,
+            },
+        )
+        .await?;
+        let wait_free = if let Some(wait_free) = sels.content_my_ticket.waitfree {
+            iso(&wait_free.charged_at)?
+        } else {
+            i64::MAX
+        };
+        let permanent = sels.content_my_ticket.ticket_rental_count
+            + sels.content_my_ticket.ticket_own_count
+            - if now() >= wait_free { 1 } else { 0 };
+        let series = states.get_srs(series_id)?;
+        series.ticket_map.insert(key, Ticket::default());
+        let mut ticket = series.get_tkt(key)?;
+        ticket.permanent = permanent;
+        ticket.wait_free = wait_free;
+        Ok(permanent)
+    }
 }
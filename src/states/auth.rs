@@ -0,0 +1,451 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use anyhow::{anyhow, Result};
+use rand::random;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::util::now;
+
+use super::States;
+
+// ordered so that every scope also grants the ones before it
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    Browse,
+    Free,
+    Tickets,
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApiKey {
+    pub name: String,
+    pub scope: Scope,
+    #[serde(default)]
+    pub created_at: i64,
+    // falls back to the quota in config.json
+    #[serde(default)]
+    pub quota: Option<Quota>,
+}
+
+// unset limits are unlimited, ticket limits count the uses of the last 24 hours
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(default)]
+pub struct Quota {
+    pub tickets_per_day: Option<usize>,
+    pub tickets_per_series: Option<usize>,
+    // shared by every route that queries upstream on behalf of the caller
+    pub requests_per_minute: Option<usize>,
+}
+
+// who a request is made on behalf of, set by the auth middleware
+#[derive(Clone)]
+pub struct Caller {
+    pub name: String,
+    pub scope: Scope,
+    pub quota: Quota,
+}
+
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub limit: &'static str,
+    pub reset_at: i64,
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} exceeded, resets at {}", self.limit, self.reset_at)
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+#[derive(Serialize, ToSchema)]
+pub struct Usage {
+    pub limit: Option<usize>,
+    pub used: usize,
+    pub remaining: Option<usize>,
+    pub reset_at: Option<i64>,
+}
+
+impl Usage {
+    fn new(limit: Option<usize>, used: usize, reset_at: Option<i64>) -> Self {
+        Self {
+            limit,
+            used,
+            remaining: limit.map(|e| e.saturating_sub(used)),
+            reset_at,
+        }
+    }
+
+    fn check(&self, limit: &'static str) -> Result<(), QuotaExceeded> {
+        match (self.remaining, self.reset_at) {
+            (Some(0), Some(reset_at)) => Err(QuotaExceeded { limit, reset_at }),
+            (Some(0), None) => Err(QuotaExceeded {
+                limit,
+                reset_at: i64::MAX,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TicketUse {
+    pub at: i64,
+    pub account: i64,
+    pub series_id: i64,
+    pub single_id: i64,
+    pub ticket_type: String,
+}
+
+// stored as the list of uses alone, reservations only live in memory
+#[derive(Serialize, Deserialize, Default)]
+#[serde(transparent)]
+pub struct TicketUses {
+    pub uses: Vec<TicketUse>,
+    // tickets being spent right now, by reservation id and series
+    #[serde(skip)]
+    reserved: Vec<(u64, i64)>,
+}
+
+impl TicketUses {
+    // the window slides, so the limit resets when its oldest use turns a day
+    // old, a reservation counts as a use made just now
+    fn usage(&self, limit: Option<usize>, series_id: Option<i64>, now: i64) -> Usage {
+        let in_series = |e: i64| series_id.is_none_or(|id| e == id);
+        let mut ats = self
+            .uses
+            .iter()
+            .filter(|e| e.at > now - DAY && in_series(e.series_id))
+            .map(|e| e.at)
+            .collect::<Vec<_>>();
+        ats.extend(self.reserved.iter().filter(|e| in_series(e.1)).map(|_| now));
+        Usage::new(limit, ats.len(), ats.iter().min().map(|e| e + DAY))
+    }
+
+    fn check(&self, quota: &Quota, series_id: i64, now: i64) -> Result<(), QuotaExceeded> {
+        self.usage(quota.tickets_per_day, None, now)
+            .check("tickets_per_day")?;
+        self.usage(quota.tickets_per_series, Some(series_id), now)
+            .check("tickets_per_series")
+    }
+}
+
+// quotas only look back a day, so each key keeps just its latest uses
+const MAX_TICKET_USES: usize = 10000;
+
+static RESERVATION_ID: AtomicU64 = AtomicU64::new(0);
+
+// holds one ticket of the caller's quotas until it is spent, dropping it
+// unspent gives the ticket back
+pub struct Reservation {
+    states: Arc<States>,
+    caller: Caller,
+    id: u64,
+    spent: bool,
+}
+
+impl Reservation {
+    pub fn caller(&self) -> &Caller {
+        &self.caller
+    }
+
+    // swaps the reservation for the use under one lock of the entry
+    pub fn spend(&mut self, ticket_use: TicketUse) {
+        let mut uses = self
+            .states
+            .ticket_uses
+            .entry(self.caller.name.clone())
+            .or_default();
+        uses.reserved.retain(|e| e.0 != self.id);
+        uses.uses.push(ticket_use);
+        if uses.uses.len() > MAX_TICKET_USES {
+            let excess = uses.uses.len() - MAX_TICKET_USES;
+            uses.uses.drain(..excess);
+        }
+        self.spent = true;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if !self.spent {
+            if let Some(mut uses) = self.states.ticket_uses.get_mut(&self.caller.name) {
+                uses.reserved.retain(|e| e.0 != self.id);
+            }
+        }
+    }
+}
+
+// counts the request unless it is over the limit
+fn hit(window: &mut (i64, usize), limit: usize, now: i64) -> Result<(), QuotaExceeded> {
+    if window.0 + MINUTE <= now {
+        *window = (now, 0);
+    }
+    if window.1 >= limit {
+        Err(QuotaExceeded {
+            limit: "requests_per_minute",
+            reset_at: window.0 + MINUTE,
+        })?
+    }
+    window.1 += 1;
+    Ok(())
+}
+
+const DAY: i64 = 86400;
+const MINUTE: i64 = 60;
+
+// api_keys.json is keyed by this, so the file never holds a usable token
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token)
+        .iter()
+        .map(|e| format!("{e:02x}"))
+        .collect()
+}
+
+impl States {
+    pub fn auth_enabled(&self) -> bool {
+        self.config.auth
+    }
+
+    // files written before tokens were hashed hold the 32 digit tokens as is
+    pub(super) fn hash_plain_tokens(&self) {
+        let plain = self
+            .api_keys
+            .iter()
+            .filter(|e| e.key().len() != 64)
+            .map(|e| e.key().clone())
+            .collect::<Vec<_>>();
+        for token in plain {
+            let (_, key) = self.api_keys.remove(&token).unwrap();
+            self.api_keys.insert(hash_token(&token), key);
+        }
+    }
+
+    fn admin_count(&self) -> usize {
+        let configured = self.config.api_keys.values();
+        let managed = self
+            .api_keys
+            .iter()
+            .filter(|e| e.scope == Scope::Admin)
+            .count();
+        configured.filter(|e| e.scope == Scope::Admin).count() + managed
+    }
+
+    // otherwise nobody could ever manage keys
+    pub(super) fn check_auth(&self) -> Result<()> {
+        if self.auth_enabled() && self.admin_count() == 0 {
+            Err(anyhow!(
+                "auth is on but no admin api key exists, add one to api_keys in config.json or set auth to false"
+            ))?
+        }
+        Ok(())
+    }
+
+    fn caller_of(&self, key: ApiKey) -> Caller {
+        Caller {
+            name: key.name,
+            scope: key.scope,
+            quota: key.quota.unwrap_or(self.config.quota),
+        }
+    }
+
+    pub fn authenticate(&self, token: &str) -> Option<Caller> {
+        let key = match self.config.api_keys.get(token) {
+            Some(key) => key.clone(),
+            None => self.api_keys.get(&hash_token(token))?.clone(),
+        };
+        Some(self.caller_of(key))
+    }
+
+    // for work done on behalf of a key outside of a request, gone once the key
+    // is revoked
+    pub fn caller_by_name(&self, name: &str) -> Option<Caller> {
+        if !self.auth_enabled() {
+            return (name == "anonymous").then(|| self.anonymous());
+        }
+        let key = match self.config.api_keys.values().find(|e| e.name == name) {
+            Some(key) => key.clone(),
+            None => self.api_keys.iter().find(|e| e.name == name)?.clone(),
+        };
+        Some(self.caller_of(key))
+    }
+
+    // used for every request while auth is off
+    pub fn anonymous(&self) -> Caller {
+        Caller {
+            name: "anonymous".to_string(),
+            scope: Scope::Admin,
+            quota: self.config.quota,
+        }
+    }
+
+    pub fn request_usage(&self, caller: &Caller) -> Usage {
+        let (used, reset_at) = match self.rate_windows.get(&caller.name) {
+            Some(window) if window.0 + MINUTE > now() => (window.1, Some(window.0 + MINUTE)),
+            _ => (0, None),
+        };
+        Usage::new(caller.quota.requests_per_minute, used, reset_at)
+    }
+
+    pub fn hit_rate_limit(&self, caller: &Caller) -> Result<(), QuotaExceeded> {
+        let Some(limit) = caller.quota.requests_per_minute else {
+            return Ok(());
+        };
+        let mut window = self.rate_windows.entry(caller.name.clone()).or_default();
+        hit(&mut window, limit, now())
+    }
+
+    pub fn ticket_usage(&self, caller: &Caller, series_id: Option<i64>) -> Usage {
+        let limit = if series_id.is_some() {
+            caller.quota.tickets_per_series
+        } else {
+            caller.quota.tickets_per_day
+        };
+        match self.ticket_uses.get(&caller.name) {
+            Some(uses) => uses.usage(limit, series_id, now()),
+            None => Usage::new(limit, 0, None),
+        }
+    }
+
+    // checked and taken under one lock of the entry, so jobs running at once
+    // can not go over a quota together
+    pub fn reserve_ticket(
+        self: &Arc<Self>,
+        caller: &Caller,
+        series_id: i64,
+    ) -> Result<Reservation, QuotaExceeded> {
+        let mut uses = self.ticket_uses.entry(caller.name.clone()).or_default();
+        uses.check(&caller.quota, series_id, now())?;
+        let id = RESERVATION_ID.fetch_add(1, Ordering::Relaxed);
+        uses.reserved.push((id, series_id));
+        Ok(Reservation {
+            states: self.clone(),
+            caller: caller.clone(),
+            id,
+            spent: false,
+        })
+    }
+
+    // key names are what ticket uses are attributed to, so they must be unique
+    pub fn create_key(&self, name: String, scope: Scope, quota: Option<Quota>) -> Result<String> {
+        let taken = self.config.api_keys.values().any(|e| e.name == name)
+            || self.api_keys.iter().any(|e| e.name == name);
+        if taken {
+            Err(anyhow!("api key {name} already exists"))?
+        }
+        let token = format!("{:032x}", random::<u128>());
+        let key = ApiKey {
+            name,
+            scope,
+            created_at: now(),
+            quota,
+        };
+        self.api_keys.insert(hash_token(&token), key);
+        Ok(token)
+    }
+
+    pub fn revoke_key(&self, name: &str) -> Result<ApiKey> {
+        if self.config.api_keys.values().any(|e| e.name == name) {
+            Err(anyhow!("api key {name} is defined in config.json"))?
+        }
+        let token = self
+            .api_keys
+            .iter()
+            .find(|e| e.name == name)
+            .map(|e| e.key().clone())
+            .ok_or_else(|| anyhow!("api key {name} does not exist"))?;
+        let (token, key) = self.api_keys.remove(&token).unwrap();
+        // removed first so two revokes at once can not both pass the check
+        if key.scope == Scope::Admin && self.auth_enabled() && self.admin_count() == 0 {
+            self.api_keys.insert(token, key);
+            return Err(anyhow!("api key {name} is the last admin key"));
+        }
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn ticket_use(at: i64, series_id: i64) -> TicketUse {
+        TicketUse {
+            at,
+            account: 1,
+            series_id,
+            single_id: 1,
+            ticket_type: "RentWaitFree".to_string(),
+        }
+    }
+
+    #[test]
+    fn usage_check_at_the_limit() {
+        assert!(Usage::new(Some(2), 1, Some(NOW)).check("limit").is_ok());
+        let e = Usage::new(Some(2), 2, Some(NOW))
+            .check("limit")
+            .unwrap_err();
+        assert_eq!((e.limit, e.reset_at), ("limit", NOW));
+        assert!(Usage::new(Some(2), 3, Some(NOW)).check("limit").is_err());
+        assert!(Usage::new(None, 100, None).check("limit").is_ok());
+    }
+
+    #[test]
+    fn ticket_window_slides() {
+        let uses = TicketUses {
+            uses: vec![ticket_use(NOW - DAY, 1), ticket_use(NOW - DAY + 10, 1)],
+            reserved: Vec::new(),
+        };
+        let usage = uses.usage(Some(1), None, NOW);
+        assert_eq!(usage.used, 1);
+        assert_eq!(usage.reset_at, Some(NOW + 10));
+        assert_eq!(uses.usage(Some(1), None, NOW + 10).used, 0);
+    }
+
+    #[test]
+    fn ticket_quotas_count_reservations() {
+        let quota = Quota {
+            tickets_per_day: Some(3),
+            tickets_per_series: Some(2),
+            requests_per_minute: None,
+        };
+        let mut uses = TicketUses {
+            uses: vec![ticket_use(NOW - 10, 1)],
+            reserved: Vec::new(),
+        };
+        assert!(uses.check(&quota, 1, NOW).is_ok());
+        uses.reserved.push((0, 1));
+        let e = uses.check(&quota, 1, NOW).unwrap_err();
+        assert_eq!(
+            (e.limit, e.reset_at),
+            ("tickets_per_series", NOW - 10 + DAY)
+        );
+        assert!(uses.check(&quota, 2, NOW).is_ok());
+        uses.reserved.push((1, 2));
+        let e = uses.check(&quota, 2, NOW).unwrap_err();
+        assert_eq!(e.limit, "tickets_per_day");
+        uses.reserved.clear();
+        assert!(uses.check(&quota, 1, NOW).is_ok());
+    }
+
+    #[test]
+    fn rate_limit_window_rolls_over() {
+        let mut window = (0, 0);
+        assert!(hit(&mut window, 2, NOW).is_ok());
+        assert!(hit(&mut window, 2, NOW + 30).is_ok());
+        let e = hit(&mut window, 2, NOW + MINUTE - 1).unwrap_err();
+        assert_eq!(e.reset_at, NOW + MINUTE);
+        assert_eq!(window, (NOW, 2));
+        assert!(hit(&mut window, 2, NOW + MINUTE).is_ok());
+        assert_eq!(window, (NOW + MINUTE, 1));
+    }
+}
@@ -1,16 +1,334 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
 
+use anyhow::{anyhow, Result};
+use chrono::{TimeZone, Utc};
+use rand::random;
 use serde::{Deserialize, Serialize};
 
+use crate::util::now;
+
+use super::{
+    account::AccountJob,
+    auth::{ApiKey, Quota},
+};
+
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
+    // only used when no listeners are given
     pub bind_addr: SocketAddr,
+    pub listeners: Vec<Listener>,
+    // serves https on every tcp listener when set
+    pub tls: Option<Tls>,
+    pub batch_queries: bool,
+    pub persisted_queries: bool,
+    pub job_limit: usize,
+    pub job_limit_per_account: usize,
+    pub shutdown_timeout: u64,
+    // keyed by operation name, responses are kept per account so one sent with
+    // an account client is never served to another
+    pub cache: HashMap<String, CachePolicy>,
+    // seconds the stored metadata of a series is served before refreshing it
+    pub series_meta_ttl: i64,
+    pub schedules: Schedules,
+    // when on, startup fails unless an admin key exists in api_keys here or in
+    // api_keys.json, turning it off opens every endpoint
+    pub auth: bool,
+    // keyed by token, these can not be revoked through the api
+    pub api_keys: HashMap<String, ApiKey>,
+    // for keys without a quota of their own
+    pub quota: Quota,
+    pub cors: Cors,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct CachePolicy {
+    pub ttl: i64,
+    #[serde(default)]
+    pub stale: i64,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             bind_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
+            listeners: Vec::new(),
+            tls: None,
+            batch_queries: false,
+            persisted_queries: false,
+            job_limit: 4,
+            job_limit_per_account: 1,
+            shutdown_timeout: 30,
+            cache: HashMap::new(),
+            series_meta_ttl: 21600,
+            schedules: Schedules::default(),
+            auth: true,
+            api_keys: HashMap::new(),
+            quota: Quota::default(),
+            cors: Cors::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn listeners(&self) -> Vec<Listener> {
+        if self.listeners.is_empty() {
+            vec![Listener::Tcp {
+                addr: self.bind_addr,
+            }]
+        } else {
+            self.listeners.clone()
+        }
+    }
+
+    // checked once at load so a bad value fails startup instead of a timer
+    pub fn validate(&self) -> Result<()> {
+        self.cors.validate()?;
+        self.schedules.validate()
+    }
+}
+
+// the mode of a unix socket is an octal string like "660"
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Listener {
+    Tcp {
+        addr: SocketAddr,
+    },
+    Unix {
+        path: PathBuf,
+        #[serde(default = "socket_mode")]
+        mode: String,
+    },
+}
+
+fn socket_mode() -> String {
+    "660".to_string()
+}
+
+// pem files, reloaded when they change on disk
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Tls {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    #[serde(default = "reload_interval")]
+    pub reload_interval: u64,
+    // answers plain http on this address with a redirect to https
+    #[serde(default)]
+    pub redirect_addr: Option<SocketAddr>,
+}
+
+fn reload_interval() -> u64 {
+    60
+}
+
+// an origin of "*" allows any origin, it can not be combined with
+// allow_credentials since that would let every site send credentials
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Cors {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age: Option<u64>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec![
+                "authorization".to_string(),
+                "content-type".to_string(),
+                "x-api-key".to_string(),
+            ],
+            exposed_headers: vec!["retry-after".to_string(), "x-ratelimit-reset".to_string()],
+            allow_credentials: false,
+            max_age: Some(600),
+        }
+    }
+}
+
+impl Cors {
+    pub fn allows(&self, origin: &str) -> bool {
+        self.allowed_origins.iter().any(|e| e == "*" || e == origin)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.allow_credentials && self.allowed_origins.iter().any(|e| e == "*") {
+            Err(anyhow!(
+                "cors can not allow credentials for every origin, list the allowed origins instead of \"*\""
+            ))?
+        }
+        Ok(())
+    }
+}
+
+// check_gotchas and check_balance used to run together on one timer, each now
+// has its own schedule and can be moved or disabled on its own
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Schedules {
+    pub refresh_token: Schedule,
+    pub check_gotchas: Schedule,
+    pub check_balance: Schedule,
+    pub check_tickets: Schedule,
+    // not an account job, disabled_accounts does not apply
+    pub check_library: Schedule,
+}
+
+impl Default for Schedules {
+    fn default() -> Self {
+        Self {
+            refresh_token: Schedule::every(3600, 0),
+            check_gotchas: Schedule::every(2400, 2400),
+            check_balance: Schedule::every(2400, 2400),
+            check_tickets: Schedule::every(9600, 9600),
+            check_library: Schedule::every(1800, 600),
+        }
+    }
+}
+
+impl Schedules {
+    pub fn get(&self, job: AccountJob) -> &Schedule {
+        match job {
+            AccountJob::RefreshToken => &self.refresh_token,
+            AccountJob::CheckGotchas => &self.check_gotchas,
+            AccountJob::CheckBalance => &self.check_balance,
+            AccountJob::CheckTickets => &self.check_tickets,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.refresh_token.validate("refresh_token")?;
+        self.check_gotchas.validate("check_gotchas")?;
+        self.check_balance.validate("check_balance")?;
+        self.check_tickets.validate("check_tickets")?;
+        self.check_library.validate("check_library")
+    }
+}
+
+// either every `interval` seconds after the last run or at the times matched by
+// `cron` (with seconds, e.g. "0 0 */2 * * *"), plus up to `jitter` seconds,
+// an enabled schedule needs one of them
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Schedule {
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub interval: u64,
+    #[serde(default)]
+    pub jitter: u64,
+    #[serde(default)]
+    pub cron: Option<String>,
+    #[serde(default)]
+    pub disabled_accounts: HashSet<i64>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl Schedule {
+    fn every(interval: u64, jitter: u64) -> Self {
+        Self {
+            enabled: true,
+            interval,
+            jitter,
+            cron: None,
+            disabled_accounts: HashSet::new(),
         }
     }
+
+    pub fn enabled_for(&self, account: i64) -> bool {
+        self.enabled && !self.disabled_accounts.contains(&account)
+    }
+
+    fn validate(&self, name: &str) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        match &self.cron {
+            Some(cron) => {
+                cron::Schedule::from_str(cron)
+                    .map_err(|e| anyhow!("schedule {name} has a bad cron expression: {e}"))?;
+            }
+            None if self.interval == 0 => Err(anyhow!(
+                "schedule {name} is enabled but has neither a cron nor an interval"
+            ))?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    pub fn next_run(&self, last_run: Option<i64>) -> Result<i64> {
+        let jitter = if self.jitter > 0 {
+            (random::<u64>() % self.jitter) as i64
+        } else {
+            0
+        };
+        let next_run = if let Some(cron) = &self.cron {
+            let after = Utc.timestamp_opt(now(), 0).unwrap();
+            cron::Schedule::from_str(cron)?
+                .after(&after)
+                .next()
+                .ok_or_else(|| anyhow!("cron expression \"{cron}\" never fires"))?
+                .timestamp()
+        } else if self.interval > 0 {
+            last_run.unwrap_or_else(now) + self.interval as i64
+        } else {
+            Err(anyhow!("schedule has neither a cron nor an interval"))?
+        };
+        Ok(next_run + jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(origins: &[&str], allow_credentials: bool) -> Cors {
+        Cors {
+            allowed_origins: origins.iter().map(|e| e.to_string()).collect(),
+            allow_credentials,
+            ..Cors::default()
+        }
+    }
+
+    #[test]
+    fn cors_allows_listed_origins() {
+        let cors = cors(&["https://a.example", "https://b.example"], false);
+        assert!(cors.allows("https://a.example"));
+        assert!(cors.allows("https://b.example"));
+        assert!(!cors.allows("https://c.example"));
+        assert!(!cors.allows("https://a.example.evil"));
+        assert!(!cors.allows("http://a.example"));
+    }
+
+    #[test]
+    fn cors_wildcard_allows_any_origin() {
+        let cors = cors(&["*"], false);
+        assert!(cors.allows("https://a.example"));
+        assert!(cors.allows("null"));
+    }
+
+    #[test]
+    fn cors_allows_nothing_by_empty_list() {
+        assert!(!cors(&[], false).allows("https://a.example"));
+    }
+
+    #[test]
+    fn cors_rejects_credentials_with_wildcard() {
+        assert!(cors(&["*"], true).validate().is_err());
+        assert!(cors(&["*"], false).validate().is_ok());
+        assert!(cors(&["https://a.example"], true).validate().is_ok());
+    }
 }
@@ -0,0 +1,222 @@
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{spawn, task::JoinHandle, time::sleep};
+use utoipa::ToSchema;
+
+use crate::{metrics::JOB_RUNS, scheduler::Job, util::now};
+
+use super::{
+    auth::{Caller, Scope},
+    series::Series,
+    States,
+};
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct Subscription {
+    // episodes first seen after this count as new
+    pub subscribed_at: i64,
+    // spends wait-free tickets of the pool on new episodes
+    pub auto_unlock: bool,
+}
+
+impl States {
+    // subscribing again only changes auto_unlock, so nothing turns new again
+    pub fn subscribe(&self, caller: &Caller, series_id: i64, auto_unlock: bool) -> Subscription {
+        let mut subscriptions = self.subscriptions.entry(caller.name.clone()).or_default();
+        let subscription = subscriptions.entry(series_id).or_insert(Subscription {
+            subscribed_at: now(),
+            auto_unlock,
+        });
+        subscription.auto_unlock = auto_unlock;
+        subscription.clone()
+    }
+
+    pub fn unsubscribe(&self, caller: &Caller, series_id: i64) -> Option<Subscription> {
+        self.subscriptions.get_mut(&caller.name)?.remove(&series_id)
+    }
+
+    pub fn subscriptions_of(&self, caller: &Caller) -> BTreeMap<i64, Subscription> {
+        self.subscriptions
+            .get(&caller.name)
+            .map(|e| e.clone())
+            .unwrap_or_default()
+    }
+
+    // every subscribed series with the names of the keys subscribed to it
+    pub fn subscribers(&self) -> BTreeMap<i64, Vec<(String, Subscription)>> {
+        let mut subscribers = BTreeMap::<i64, Vec<_>>::new();
+        for subscriptions in self.subscriptions.iter() {
+            for (series_id, subscription) in subscriptions.iter() {
+                subscribers
+                    .entry(*series_id)
+                    .or_default()
+                    .push((subscriptions.key().clone(), subscription.clone()));
+            }
+        }
+        subscribers
+    }
+
+    // new episodes not marked as read, oldest first
+    pub fn unread_new(&self, caller: &Caller, series_id: i64) -> Vec<i64> {
+        let Some(subscription) = self.subscriptions_of(caller).remove(&series_id) else {
+            return Vec::new();
+        };
+        let progress = self.series_progress(caller, series_id);
+        self.serieses
+            .get(&series_id)
+            .map(|series| {
+                series
+                    .episodes
+                    .iter()
+                    .filter(|e| e.seen_at > subscription.subscribed_at)
+                    .filter(|e| !progress.singles.get(&e.single_id).is_some_and(|e| e.read))
+                    .map(|e| e.single_id)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // a new episode is unlocked for the first opted in subscriber that may
+    // still spend a ticket, once no wait-free ticket is left the rest of the
+    // series waits for the next run
+    async fn check_library(
+        self: &Arc<Self>,
+        series_id: i64,
+        subscribers: Vec<(String, Subscription)>,
+    ) -> Result<()> {
+        let new = Series::check_new_episodes(self, series_id).await?;
+        if !new.is_empty() {
+            info!("found {} new episodes of series {series_id}", new.len());
+        }
+        let unlockers = subscribers
+            .iter()
+            .filter(|(_, e)| e.auto_unlock)
+            .filter_map(|(name, e)| Some((self.caller_by_name(name)?, e.subscribed_at)))
+            .filter(|(caller, _)| caller.scope >= Scope::Tickets)
+            .collect::<Vec<_>>();
+        let Some(since) = unlockers.iter().map(|(_, e)| *e).min() else {
+            return Ok(());
+        };
+        // picked from the stored list rather than what this run found, so
+        // episodes first stored by another endpoint, or left locked when a
+        // run ran out of wait-free tickets, are tried again on every run
+        let locked = match self.serieses.get(&series_id) {
+            Some(series) => series
+                .episodes
+                .iter()
+                .filter(|e| e.seen_at > since && !e.free)
+                .filter(|e| !series.single_map.contains_key(&e.single_id))
+                .map(|e| (e.single_id, e.seen_at))
+                .collect::<Vec<_>>(),
+            None => return Ok(()),
+        };
+        'episodes: for (single_id, seen_at) in locked {
+            for (caller, subscribed_at) in &unlockers {
+                // only episodes published after the caller subscribed
+                if seen_at <= *subscribed_at {
+                    continue;
+                }
+                let Ok(mut reservation) = self.reserve_ticket(caller, series_id) else {
+                    continue;
+                };
+                match Series::unlock_wait_free(self, &mut reservation, series_id, single_id).await {
+                    Ok(Some(_)) => {
+                        info!("unlocked {series_id}/{single_id} for {}", caller.name);
+                        continue 'episodes;
+                    }
+                    // out of wait-free tickets, the rest are picked again
+                    // on the next run
+                    Ok(None) => break 'episodes,
+                    Err(e) => {
+                        warn!("failed to unlock {series_id}/{single_id}: {e}");
+                        continue 'episodes;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn run_library_job(
+        self: Arc<Self>,
+        series_id: i64,
+        subscribers: Vec<(String, Subscription)>,
+    ) -> Result<()> {
+        let states = self.clone();
+        let scheduled = Job::new(format!("check_library {series_id}"));
+        self.scheduler
+            .clone()
+            .spawn(scheduled, async move {
+                let started_at = now();
+                let start = Instant::now();
+                let result = states.check_library(series_id, subscribers).await;
+                let outcome = if result.is_ok() { "success" } else { "failure" };
+                JOB_RUNS.inc(&[("job", "check_library"), ("outcome", outcome)]);
+                states
+                    .library_history
+                    .entry(series_id)
+                    .or_default()
+                    .finish(started_at, start, &result);
+                if let Err(e) = &result {
+                    warn!("failed to check series {series_id} for new episodes: {e}");
+                }
+                result
+            })
+            .await?
+    }
+
+    // every subscribed series gets a job of its own, so one slow series does
+    // not hold the others up
+    pub(super) fn start_library_timer(self: &Arc<Self>) -> JoinHandle<()> {
+        let states = self.clone();
+        spawn(async move {
+            let schedule = states.config.schedules.check_library.clone();
+            if !schedule.enabled {
+                return;
+            }
+            let mut last_run = None;
+            loop {
+                let next_run = match schedule.next_run(last_run) {
+                    Ok(next_run) => next_run,
+                    Err(e) => {
+                        warn!("not scheduling check_library: {e}");
+                        break;
+                    }
+                };
+                let subscribers = states.subscribers();
+                states
+                    .library_history
+                    .retain(|k, _| subscribers.contains_key(k));
+                for series_id in subscribers.keys() {
+                    states
+                        .library_history
+                        .entry(*series_id)
+                        .or_default()
+                        .next_run = Some(next_run);
+                }
+                let diff = next_run - now();
+                if diff > 0 {
+                    sleep(Duration::from_secs(diff as u64)).await;
+                }
+                let jobs = states
+                    .subscribers()
+                    .into_iter()
+                    .map(|(series_id, subscribers)| {
+                        spawn(states.clone().run_library_job(series_id, subscribers))
+                    })
+                    .collect::<Vec<_>>();
+                for job in jobs {
+                    let _ = job.await;
+                }
+                last_run = Some(now());
+            }
+        })
+    }
+}
@@ -1,28 +1,40 @@
 use std::{
+    collections::{BTreeMap, HashMap},
     fs::{self, File},
     io::{BufReader, BufWriter},
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result};
 use axum::http::HeaderMap;
 use dashmap::{mapref::one::RefMut, DashMap};
 use log::{info, warn};
-use rand::random;
 use reqwest::Client;
-use tokio::{spawn, sync::broadcast::Receiver, time::sleep};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{spawn, sync::broadcast::Receiver, task::JoinHandle, time::sleep};
 
-use crate::util::{now, spawn_solo};
+use crate::{
+    graphql::Upstream,
+    metrics::JOB_RUNS,
+    scheduler::{Job, Scheduler},
+    util::now,
+};
 
 use self::{
-    account::{generate_agent, Account},
+    account::{generate_agent, Account, AccountJob},
+    auth::{ApiKey, TicketUses},
     config::Config,
+    library::Subscription,
+    progress::SeriesProgress,
     series::Series,
 };
 
 pub mod account;
+pub mod auth;
 pub mod config;
+pub mod library;
+pub mod progress;
 pub mod series;
 
 pub struct States {
@@ -30,17 +42,80 @@ pub struct States {
     pub serieses: DashMap<i64, Series>,
     pub find_map: DashMap<i64, Receiver<bool>>,
     pub config: Config,
-    pub client: Client,
+    pub client: Upstream,
+    pub scheduler: Arc<Scheduler>,
+    pub job_history: DashMap<(i64, AccountJob), JobRecord>,
+    // check_library runs once per subscribed series, keyed by series
+    pub library_history: DashMap<i64, JobRecord>,
+    // keyed by token
+    pub api_keys: DashMap<String, ApiKey>,
+    // keyed by api key name
+    pub ticket_uses: DashMap<String, TicketUses>,
+    // keyed by api key name and then series id
+    pub progress: DashMap<String, HashMap<i64, SeriesProgress>>,
+    // keyed by api key name and then series id
+    pub subscriptions: DashMap<String, BTreeMap<i64, Subscription>>,
+    // start and request count of the current minute by api key name
+    pub rate_windows: DashMap<String, (i64, usize)>,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct JobRecord {
+    pub last_run: Option<i64>,
+    pub next_run: Option<i64>,
+    pub duration_ms: Option<u64>,
+    pub outcome: Option<String>,
+}
+
+impl JobRecord {
+    fn finish(&mut self, started_at: i64, start: Instant, result: &Result<()>) {
+        self.last_run = Some(started_at);
+        self.duration_ms = Some(start.elapsed().as_millis() as u64);
+        self.outcome = Some(match result {
+            Ok(()) => "success".to_string(),
+            Err(e) => format!("failure: {e}"),
+        });
+    }
+}
+
+fn load_file<T: DeserializeOwned + Default>(name: &str) -> Result<T> {
+    if let Ok(reader) = File::open(format!("{name}.json")) {
+        info!("loading {name}");
+        let reader = BufReader::new(reader);
+        Ok(serde_json::from_reader(reader)?)
+    } else {
+        warn!("{name}.json not found, using default value");
+        Ok(Default::default())
+    }
+}
+
+// writes next to the old file first so a crash never leaves a truncated one
+fn save_file(name: &str, value: &impl Serialize) -> Result<()> {
+    info!("saving {name}");
+    let writer = File::create(format!("{name}.json.new"))?;
+    let writer = BufWriter::new(writer);
+    serde_json::to_writer_pretty(writer, value)?;
+    let _ = fs::remove_file(format!("{name}.json.old"));
+    let _ = fs::rename(format!("{name}.json"), format!("{name}.json.old"));
+    let _ = fs::rename(format!("{name}.json.new"), format!("{name}.json"));
+    Ok(())
 }
 
 impl States {
-    pub fn get_acc(&self, key: i64) -> Result<RefMut<i64, Account>> {
+    pub fn get_acc(&self, key: i64) -> Result<RefMut<'_, i64, Account>> {
         self.accounts
             .get_mut(&key)
             .with_context(move || format!("account {key} does not exist"))
     }
 
-    pub fn get_srs(&self, key: i64) -> Result<RefMut<i64, Series>> {
+    // a guard from get_acc must not be held across an await, so requests take
+    // the client through here
+    pub fn acc_client(&self, key: i64) -> Result<Upstream> {
+        let client = self.get_acc(key)?.client();
+        Ok(Upstream::account(client, key))
+    }
+
+    pub fn get_srs(&self, key: i64) -> Result<RefMut<'_, i64, Series>> {
         if let Some(series) = self.serieses.get_mut(&key) {
             Ok(series)
         } else {
@@ -50,122 +125,124 @@ impl States {
     }
 
     pub fn load() -> Result<Arc<Self>> {
-        Ok(Arc::new(Self {
-            accounts: if let Ok(reader) = File::open("accounts.json") {
-                info!("loading accounts");
-                let reader = BufReader::new(reader);
-                serde_json::from_reader(reader)?
-            } else {
-                warn!("accounts.json not found, using default value");
-                Default::default()
-            },
-            serieses: if let Ok(reader) = File::open("serieses.json") {
-                info!("loading serieses");
-                let reader = BufReader::new(reader);
-                serde_json::from_reader(reader)?
-            } else {
-                warn!("serieses.json not found, using default value");
-                Default::default()
-            },
+        let config: Config = load_file("config")?;
+        config.validate()?;
+        let states = Arc::new(Self {
+            accounts: load_file("accounts")?,
+            serieses: load_file("serieses")?,
             find_map: { DashMap::new() },
-            config: if let Ok(reader) = File::open("config.json") {
-                info!("loading config");
-                let reader = BufReader::new(reader);
-                serde_json::from_reader(reader)?
-            } else {
-                warn!("config.json not found, using default value");
-                Default::default()
-            },
+            scheduler: Arc::new(Scheduler::new(
+                config.job_limit,
+                config.job_limit_per_account,
+            )),
+            config,
+            job_history: DashMap::new(),
+            library_history: DashMap::new(),
+            api_keys: load_file("api_keys")?,
+            ticket_uses: load_file("ticket_uses")?,
+            progress: load_file("progress")?,
+            subscriptions: load_file("subscriptions")?,
+            rate_windows: DashMap::new(),
             client: {
                 let mut headers = HeaderMap::new();
                 headers.insert("referer", "https://page.kakao.com".parse()?);
-                Client::builder()
-                    .user_agent(generate_agent())
-                    .default_headers(headers)
-                    .build()?
+                Upstream::shared(
+                    Client::builder()
+                        .user_agent(generate_agent())
+                        .default_headers(headers)
+                        .build()?,
+                )
             },
-        }))
+        });
+        states.hash_plain_tokens();
+        states.check_auth()?;
+        Ok(states)
     }
 
-    // todo: abstract the remove/rename/rename
     pub fn save(&self) -> Result<()> {
-        info!("saving accounts");
-        let accounts_writer = File::create("accounts.json.new")?;
-        let accounts_writer = BufWriter::new(accounts_writer);
-        serde_json::to_writer_pretty(accounts_writer, &self.accounts)?;
-        let _ = fs::remove_file("accounts.json.old");
-        let _ = fs::rename("accounts.json", "accounts.json.old");
-        let _ = fs::rename("accounts.json.new", "accounts.json");
-        info!("saving serieses");
-        let serieses_writer = File::create("serieses.json.new")?;
-        let serieses_writer = BufWriter::new(serieses_writer);
-        serde_json::to_writer_pretty(serieses_writer, &self.serieses)?;
-        let _ = fs::remove_file("serieses.json.old");
-        let _ = fs::rename("serieses.json", "serieses.json.old");
-        let _ = fs::rename("serieses.json.new", "serieses.json");
-        info!("saving config");
-        let config_writer = File::create("config.json.new")?;
-        let config_writer = BufWriter::new(config_writer);
-        serde_json::to_writer_pretty(config_writer, &self.config)?;
-        let _ = fs::remove_file("config.json.old");
-        let _ = fs::rename("config.json", "config.json.old");
-        let _ = fs::rename("config.json.new", "config.json");
+        save_file("accounts", &self.accounts)?;
+        save_file("serieses", &self.serieses)?;
+        save_file("config", &self.config)?;
+        save_file("api_keys", &self.api_keys)?;
+        save_file("ticket_uses", &self.ticket_uses)?;
+        save_file("progress", &self.progress)?;
+        save_file("subscriptions", &self.subscriptions)?;
         Ok(())
     }
 
-    pub fn start_timers(self: &Arc<Self>) {
-        for key in self.accounts.iter().map(|e| *e.key()) {
-            let states = self.clone();
-            spawn(async move {
-                loop {
-                    let states = states.clone();
-                    let diff = now() - states.get_acc(key).unwrap().last_token_refresh;
-                    if diff < 3600 {
-                        sleep(Duration::from_secs(3600 - diff as u64)).await;
-                    }
-                    let _ = spawn_solo(async move {
-                        if let Err(e) = Account::refresh_token(&states, key).await {
-                            warn!("failed to refresh token for account {key}: {e}")
-                        } else {
-                            info!("refreshed token for account {key}")
-                        }
-                        states.get_acc(key).unwrap().last_token_refresh = now();
-                    })
-                    .await;
+    // runs an account job through the scheduler and keeps its history
+    pub async fn run_job(self: Arc<Self>, key: i64, job: AccountJob) -> Result<()> {
+        let states = self.clone();
+        let scheduled = Job::new(job.name()).account(key);
+        self.scheduler
+            .clone()
+            .spawn(scheduled, async move {
+                let started_at = now();
+                let start = Instant::now();
+                let result = job.run(&states, key).await;
+                let outcome = if result.is_ok() { "success" } else { "failure" };
+                JOB_RUNS.inc(&[("job", job.name()), ("outcome", outcome)]);
+                states
+                    .job_history
+                    .entry((key, job))
+                    .or_default()
+                    .finish(started_at, start, &result);
+                match &result {
+                    Ok(()) => info!("finished {} for account {key}", job.name()),
+                    Err(e) => warn!("failed to {} for account {key}: {e}", job.describe()),
                 }
-            });
-            let states = self.clone();
-            spawn(async move {
-                loop {
-                    sleep(Duration::from_secs(2400 + random::<u64>() % 2400)).await;
-                    let states = states.clone();
-                    spawn_solo(async move {
-                        if let Err(e) = Account::check_gotchas(&states, key).await {
-                            warn!("failed to check gotchas for account {key}: {e}")
-                        }
-                        if let Err(e) = Account::check_balance(&states, key).await {
-                            warn!("failed to check balance for account {key}: {e}")
-                        }
-                    });
+                result
+            })
+            .await?
+    }
+
+    // the returned handles are aborted on shutdown, jobs already handed to the
+    // scheduler keep running until it is drained
+    pub fn start_timers(self: &Arc<Self>) -> Vec<JoinHandle<()>> {
+        let mut timers = Vec::new();
+        for key in self.accounts.iter().map(|e| *e.key()) {
+            for job in AccountJob::ALL {
+                let schedule = self.config.schedules.get(job);
+                if !schedule.enabled_for(key) {
+                    continue;
                 }
-            });
-            let states = self.clone();
-            spawn(async move {
-                loop {
-                    sleep(Duration::from_secs(9600 + random::<u64>() % 9600)).await;
-                    let states = states.clone();
-                    spawn_solo(async move {
-                        if let Err(e) = Account::check_tickets(&states, key).await {
-                            warn!("failed to check tickets for account {key}: {e}")
+                let schedule = schedule.clone();
+                let states = self.clone();
+                timers.push(spawn(async move {
+                    let mut last_run = if job == AccountJob::RefreshToken {
+                        Some(states.get_acc(key).unwrap().last_token_refresh)
+                    } else {
+                        None
+                    };
+                    loop {
+                        let next_run = match schedule.next_run(last_run) {
+                            Ok(next_run) => next_run,
+                            Err(e) => {
+                                warn!("not scheduling {} for account {key}: {e}", job.name());
+                                break;
+                            }
+                        };
+                        states.job_history.entry((key, job)).or_default().next_run = Some(next_run);
+                        let diff = next_run - now();
+                        if diff > 0 {
+                            sleep(Duration::from_secs(diff as u64)).await;
                         }
-                    });
-                }
-            });
+                        let _ = states.clone().run_job(key, job).await;
+                        last_run = Some(now());
+                    }
+                }));
+            }
         }
+        timers.push(self.start_library_timer());
         let states = self.clone();
-        std::thread::spawn(move || loop {
-            std::thread::sleep(Duration::from_secs(3600));
-            states.clone().save().unwrap();
-        });
+        timers.push(spawn(async move {
+            loop {
+                sleep(Duration::from_secs(3600)).await;
+                if let Err(e) = states.save() {
+                    warn!("failed to save states: {e}")
+                }
+            }
+        }));
+        timers
     }
 }
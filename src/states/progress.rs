@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::util::now;
+
+use super::{auth::Caller, States};
+
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
+pub struct SeriesProgress {
+    pub last_single: Option<i64>,
+    pub last_read_at: i64,
+    // keyed by single id, singles never opened are simply missing
+    pub singles: HashMap<i64, ReadMark>,
+}
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct ReadMark {
+    pub read: bool,
+    pub at: i64,
+}
+
+impl States {
+    // called for every single served, so it also moves where to continue from
+    pub fn record_read(&self, caller: &Caller, series_id: i64, single_id: i64) {
+        let mut progress = self.progress.entry(caller.name.clone()).or_default();
+        let series = progress.entry(series_id).or_default();
+        series.last_single = Some(single_id);
+        series.last_read_at = now();
+        series.singles.insert(
+            single_id,
+            ReadMark {
+                read: true,
+                at: now(),
+            },
+        );
+    }
+
+    // marking by hand leaves where to continue from alone
+    pub fn mark_read(&self, caller: &Caller, series_id: i64, single_id: i64, read: bool) {
+        let mut progress = self.progress.entry(caller.name.clone()).or_default();
+        let series = progress.entry(series_id).or_default();
+        series
+            .singles
+            .insert(single_id, ReadMark { read, at: now() });
+    }
+
+    pub fn series_progress(&self, caller: &Caller, series_id: i64) -> SeriesProgress {
+        self.progress
+            .get(&caller.name)
+            .and_then(|e| e.get(&series_id).cloned())
+            .unwrap_or_default()
+    }
+}
@@ -1,22 +1,414 @@
-use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
 use dashmap::{mapref::one::RefMut, DashMap};
+use log::warn;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use vitis_be_macros::macroql;
+
+use crate::{
+    graphql::{GraphQlError, Upstream, NOT_ENOUGH_TICKETS, SESSION_EXPIRED},
+    util::{get_param, now},
+};
+
+use super::{account::Account, auth::Reservation, States};
 
 #[derive(Default, Serialize, Deserialize)]
 pub struct Series {
     pub single_map: DashMap<i64, Single>,
     pub ticket_map: DashMap<i64, Ticket>,
+    // in reading order
+    #[serde(default)]
+    pub episodes: Vec<Episode>,
+    #[serde(default)]
+    pub episodes_checked_at: i64,
+    #[serde(default)]
+    pub meta: Option<Meta>,
+}
+
+// the rating is kept as upstream sends it, an average of no ratings can not
+// be stored as json
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Meta {
+    pub cover: String,
+    pub title: String,
+    pub pub_period: Option<String>,
+    pub author: String,
+    pub description: String,
+    pub genre: Option<String>,
+    pub age_rating: Option<String>,
+    // in minutes, missing for series without wait-free
+    pub wait_free_interval: Option<i64>,
+    pub episode_count: Option<i64>,
+    pub view_count: i64,
+    pub rating_count: i64,
+    pub rating_sum: i64,
+    pub fetched_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct Episode {
+    pub number: i64,
+    pub single_id: i64,
+    pub title: String,
+    pub row_1: String,
+    pub row_2: Option<String>,
+    pub free: bool,
+    // when this server first found the episode, not when it was published
+    #[serde(default)]
+    pub seen_at: i64,
+}
+
+// how long the episode list is trusted before looking for new episodes
+const EPISODES_TTL: i64 = 600;
+
+const EPISODES_PAGE_SIZE: i32 = 25;
+
+macroql! {
+    pub query series_meta (
+        sortType: String,
+        seriesId: Long
+    ) {
+        contentHomeOverview(seriesId) {
+            content: {
+                thumbnail: String,
+                title: String,
+                authors: String,
+                pubPeriod: String?,
+                subcategory: String?,
+                ageGrade: String?,
+                waitfreePeriodByMinute: Long?,
+                serviceProperty: {
+                    viewCount: Long,
+                    ratingCount: Long,
+                    ratingSum: Long
+                }
+            }
+        },
+        contentHomeAbout(seriesId) {
+            description: String,
+        },
+        contentHomeProductList(sortType, seriesId) {
+            totalCount: Long?
+        }
+    }
+}
+
+macroql! {
+    query episode_list (
+        sortType: String,
+        seriesId: Long,
+        after: String?,
+        first: Int
+    ) {
+        contentHomeProductList(sortType, seriesId, after, first) {
+            pageInfo {
+                hasNextPage: Boolean
+            },
+            edges: [] {
+                node: {
+                    row1: {
+                        title: String
+                    },
+                    row2: [String],
+                    row3: String?,
+                    scheme: String,
+                    single: ? {
+                        isFree: Boolean
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl Series {
-    pub fn get_tkt(&self, key: i64) -> Result<RefMut<i64, Ticket>> {
+    pub fn get_tkt(&self, key: i64) -> Result<RefMut<'_, i64, Ticket>> {
         self.ticket_map
             .get_mut(&key)
             .with_context(move || format!("account {key} does not exist for this series"))
     }
+
+    pub async fn fetch_single(
+        states: &States,
+        client: Upstream,
+        series_id: i64,
+        single_id: i64,
+    ) -> Result<Single> {
+        macroql! {
+            query viewer (
+                seriesId: Long,
+                productId: Long
+            ) {
+                viewerInfo(seriesId, productId) {
+                    item {
+                        title: String
+                    },
+                    viewerData {
+                        ... ImageViewerData {
+                            imageDownloadData {
+                                files: [] {
+                                    size: Long,
+                                    secureUrl: String,
+                                }
+                            }
+                        },
+                        ... TextViewerData {
+                            contentsList: [] {
+                                chapterId: Long,
+                                contentId: Long,
+                                secureUrl: String
+                            }
+                        }
+                    },
+                    prevItem: ? {
+                        productId: Long,
+                    },
+                    nextItem: ? {
+                        productId: Long,
+                    }
+                }
+            }
+        }
+        use viewer::sels::viewer_info::ViewerData;
+        let sels = viewer(
+            client,
+            viewer::Vars {
+                series_id,
+                product_id: single_id,
+            },
+        )
+        .await?;
+        let single = Single {
+            title: sels.viewer_info.item.title,
+            viewer: match sels.viewer_info.viewer_data {
+                ViewerData::ImageViewerData {
+                    image_download_data,
+                } => {
+                    let mut images = Vec::new();
+                    for file in image_download_data.files {
+                        images.push(Image {
+                            size: file.size,
+                            kid: get_param(&file.secure_url, "kid")?,
+                        })
+                    }
+                    Viewer::ImageList(images)
+                }
+                ViewerData::TextViewerData { contents_list } => {
+                    let mut khtmls = Vec::new();
+                    for content in contents_list {
+                        khtmls.push(KHTML {
+                            chapter_id: content.chapter_id,
+                            content_id: content.content_id,
+                            kid: get_param(&format!("kid={}", content.secure_url), "kid")?,
+                        })
+                    }
+                    Viewer::KakaoHTML(khtmls)
+                }
+                ViewerData::Unknown => Err(anyhow!("unsupported single type"))?,
+            },
+            prev: sels.viewer_info.prev_item.map(|e| e.product_id),
+            next: sels.viewer_info.next_item.map(|e| e.product_id),
+        };
+        states
+            .get_srs(series_id)?
+            .single_map
+            .insert(single_id, single.clone());
+        Ok(single)
+    }
+
+    // tries every account whose wait-free ticket is charged, none being left is not
+    // an error, upstream refusing an account passes it over while a request that
+    // never got an answer is returned
+    pub async fn unlock_wait_free(
+        states: &States,
+        reservation: &mut Reservation,
+        series_id: i64,
+        single_id: i64,
+    ) -> Result<Option<Single>> {
+        let wait_frees = states
+            .get_srs(series_id)?
+            .ticket_map
+            .iter_mut()
+            .filter_map(|e| {
+                if now() > e.wait_free {
+                    Some(*e.key())
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<i64>>();
+        for account_id in wait_frees {
+            let used = Account::use_ticket(
+                states,
+                reservation,
+                account_id,
+                series_id,
+                single_id,
+                "RentWaitFree",
+            )
+            .await;
+            match used {
+                Ok(()) => {
+                    let client = states.acc_client(account_id)?;
+                    return Ok(Some(
+                        Self::fetch_single(states, client, series_id, single_id).await?,
+                    ));
+                }
+                Err(e) => match e.downcast_ref::<GraphQlError>() {
+                    Some(error) if error.has_code(NOT_ENOUGH_TICKETS) => {}
+                    Some(error) if error.has_code(SESSION_EXPIRED) => {
+                        warn!("session of account {account_id} expired, skipping it: {e}")
+                    }
+                    Some(error) => warn!(
+                        "account {account_id} could not use a ticket ({:?}), skipping it: {e}",
+                        error.codes()
+                    ),
+                    None => return Err(e),
+                },
+            }
+        }
+        Ok(None)
+    }
+
+    // served from the local copy while it is younger than series_meta_ttl, and
+    // still when upstream fails to give a newer one
+    pub async fn sync_meta(states: &States, key: i64, force: bool) -> Result<Meta> {
+        if let Some(meta) = Self::fresh_meta(states, key, force) {
+            return Ok(meta);
+        }
+        let sels = series_meta(states.client.clone(), Self::meta_vars(key)).await;
+        Self::store_meta(states, key, sels)
+    }
+
+    pub fn fresh_meta(states: &States, key: i64, force: bool) -> Option<Meta> {
+        let meta = states.serieses.get(&key)?.meta.clone();
+        meta.filter(|e| !force && now() - e.fetched_at < states.config.series_meta_ttl)
+    }
+
+    pub fn meta_vars(key: i64) -> series_meta::Vars {
+        series_meta::Vars {
+            sort_type: String::new(),
+            series_id: key,
+        }
+    }
+
+    // for callers that fetched the meta themselves, e.g. in a batch, the series
+    // is only stored once upstream has answered for it
+    pub fn store_meta(states: &States, key: i64, sels: Result<series_meta::Sels>) -> Result<Meta> {
+        let cached = states.serieses.get(&key).and_then(|e| e.meta.clone());
+        let sels = match (sels, cached) {
+            (Ok(sels), _) => sels,
+            (Err(e), Some(meta)) => {
+                warn!("failed to refresh meta of series {key}, serving a stale one: {e}");
+                return Ok(meta);
+            }
+            (Err(e), None) => return Err(e),
+        };
+        let content = sels.content_home_overview.content;
+        let meta = Meta {
+            cover: get_param(&content.thumbnail, "kid")?,
+            title: content.title,
+            pub_period: content.pub_period,
+            author: content.authors,
+            description: sels.content_home_about.description,
+            genre: content.subcategory,
+            age_rating: content.age_grade,
+            wait_free_interval: content.waitfree_period_by_minute,
+            episode_count: sels.content_home_product_list.total_count,
+            view_count: content.service_property.view_count,
+            rating_count: content.service_property.rating_count,
+            rating_sum: content.service_property.rating_sum,
+            fetched_at: now(),
+        };
+        states.get_srs(key)?.meta = Some(meta.clone());
+        Ok(meta)
+    }
+
+    // new episodes are only ever appended, so unless `full` is set the walk
+    // starts at the last known page, which is fetched again since episodes on
+    // it may have turned free since
+    pub async fn sync_episodes(states: &States, key: i64, full: bool) -> Result<Vec<Episode>> {
+        if let Some(series) = states.serieses.get(&key) {
+            if !full && now() - series.episodes_checked_at < EPISODES_TTL {
+                return Ok(series.episodes.clone());
+            }
+        }
+        let offset = if full { 0 } else { Self::overlap(states, key) };
+        Self::fetch_episodes(states, key, offset).await
+    }
+
+    // ignores the ttl and returns only the episodes that were not known before
+    pub async fn check_new_episodes(states: &States, key: i64) -> Result<Vec<Episode>> {
+        let known = states.serieses.get(&key).map_or(0, |e| e.episodes.len());
+        let list = Self::fetch_episodes(states, key, Self::overlap(states, key)).await?;
+        Ok(list.get(known..).unwrap_or_default().to_vec())
+    }
+
+    fn overlap(states: &States, key: i64) -> usize {
+        let known = states.serieses.get(&key).map_or(0, |e| e.episodes.len());
+        known.saturating_sub(EPISODES_PAGE_SIZE as usize)
+    }
+
+    async fn fetch_episodes(states: &States, key: i64, offset: usize) -> Result<Vec<Episode>> {
+        let mut list = Vec::new();
+        loop {
+            let skip = offset + list.len();
+            let sels = episode_list(
+                states.client.clone(),
+                episode_list::Vars {
+                    sort_type: "asc".to_string(),
+                    series_id: key,
+                    // upstream cursors are plain offsets
+                    after: (skip > 0).then(|| skip.to_string()),
+                    first: EPISODES_PAGE_SIZE,
+                },
+            )
+            .await?;
+            let edges = sels.content_home_product_list.edges;
+            let done = edges.is_empty() || !sels.content_home_product_list.page_info.has_next_page;
+            for edge in edges {
+                list.push(Episode {
+                    number: (offset + list.len() + 1) as i64,
+                    single_id: get_param(&edge.node.scheme, "product_id")?.parse()?,
+                    title: edge.node.row_1.title,
+                    row_1: edge.node.row_2.join(" · "),
+                    row_2: edge.node.row_3,
+                    free: edge.node.single.is_some_and(|e| e.is_free),
+                    seen_at: now(),
+                });
+            }
+            if done {
+                break;
+            }
+        }
+        // an id upstream does not know gives an empty list instead of an error,
+        // it must not be stored as a series
+        if list.is_empty() && !states.serieses.contains_key(&key) {
+            Err(anyhow!("series {key} does not exist"))?
+        }
+        let mut series = states.get_srs(key)?;
+        // a walk finds the known episodes again, they keep when they were
+        // first seen
+        let seen = series
+            .episodes
+            .iter()
+            .map(|e| (e.single_id, e.seen_at))
+            .collect::<HashMap<_, _>>();
+        for episode in list.iter_mut() {
+            if let Some(seen_at) = seen.get(&episode.single_id) {
+                episode.seen_at = *seen_at;
+            }
+        }
+        series.episodes.truncate(offset);
+        series.episodes.extend(list);
+        series.episodes_checked_at = now();
+        Ok(series.episodes.clone())
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub struct Single {
     pub title: String,
     pub viewer: Viewer,
@@ -24,20 +416,20 @@ pub struct Single {
     pub next: Option<i64>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 #[serde(tag = "type", content = "data")]
 pub enum Viewer {
     ImageList(Vec<Image>),
     KakaoHTML(Vec<KHTML>),
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub struct Image {
     pub size: i64,
     pub kid: String,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub struct KHTML {
     pub chapter_id: i64,
     pub content_id: i64,
@@ -0,0 +1,77 @@
+use std::{
+    fs,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect},
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use log::{info, warn};
+use tokio::{spawn, task::JoinHandle, time::sleep};
+
+use crate::states::config::Tls;
+
+pub async fn load(config: &Tls) -> Result<RustlsConfig> {
+    RustlsConfig::from_pem_file(&config.cert_path, &config.key_path)
+        .await
+        .with_context(|| {
+            format!(
+                "failed to load tls certificate {} with key {}",
+                config.cert_path.display(),
+                config.key_path.display()
+            )
+        })
+}
+
+fn modified(config: &Tls) -> Option<(SystemTime, SystemTime)> {
+    let cert = fs::metadata(&config.cert_path).ok()?.modified().ok()?;
+    let key = fs::metadata(&config.key_path).ok()?.modified().ok()?;
+    Some((cert, key))
+}
+
+// polls the modification times so renewed certificates are picked up without a
+// restart, connections already open keep the old one
+pub fn watch(rustls: RustlsConfig, config: Tls) -> JoinHandle<()> {
+    spawn(async move {
+        let mut last = modified(&config);
+        loop {
+            sleep(Duration::from_secs(config.reload_interval)).await;
+            let current = modified(&config);
+            if current.is_none() || current == last {
+                continue;
+            }
+            match rustls
+                .reload_from_pem_file(&config.cert_path, &config.key_path)
+                .await
+            {
+                Ok(()) => info!("reloaded tls certificate"),
+                Err(e) => warn!("failed to reload tls certificate: {e}"),
+            }
+            last = current;
+        }
+    })
+}
+
+// sends plain http requests to the same host on the https port
+pub fn redirect(port: u16) -> Router {
+    Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+        let Some(host) = headers.get(header::HOST).and_then(|e| e.to_str().ok()) else {
+            return (StatusCode::BAD_REQUEST, "missing host header").into_response();
+        };
+        let host = host
+            .rsplit_once(':')
+            .filter(|(_, port)| port.parse::<u16>().is_ok())
+            .map_or(host, |(host, _)| host);
+        let path = uri.path_and_query().map_or("/", |e| e.as_str());
+        let url = if port == 443 {
+            format!("https://{host}{path}")
+        } else {
+            format!("https://{host}:{port}{path}")
+        };
+        Redirect::permanent(&url).into_response()
+    })
+}